@@ -13,20 +13,20 @@ use clap::Parser;
 use humansize::{format_size, BINARY};
 use memmap::MmapOptions;
 use omnixtend_rs::cache::Cache;
+use omnixtend_rs::config::ConnectionConfig;
 use omnixtend_rs::connection::{Connection, ConnectionState};
 use omnixtend_rs::operations::{
     Operations, ReadOpLen, TLOperations, TLResult, WriteOpLen, WriteOpPartial,
 };
-use omnixtend_rs::tick::Tick;
-use omnixtend_rs::utils::process_packet;
-use pnet::datalink::Channel::Ethernet;
-use pnet::datalink::{self, DataLinkSender};
-use pnet::datalink::{DataLinkReceiver, NetworkInterface};
+use omnixtend_rs::runner::{Requester, Runner};
+use omnixtend_rs::tick::{Liveness, Tick};
+use omnixtend_rs::transport::{DatalinkTransport, Transport, UdpTransport};
 use pnet::util::{MacAddr, ParseMacAddrErr};
 use rayon::prelude::*;
 use snafu::prelude::*;
 use std::fs::File;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::{self, FromStr};
 use std::sync::atomic::AtomicBool;
@@ -37,11 +37,19 @@ use std::time::{Duration, Instant};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("IO Error: {}", source))]
-    IOError { source: std::io::Error },
+    #[snafu(display("Transport Error: {}", source))]
+    TransportError {
+        source: omnixtend_rs::transport::Error,
+    },
+
+    #[snafu(display("Either an interface or UDP addresses are required."))]
+    NoTransport {},
 
-    #[snafu(display("Could not find interface: {}", name))]
-    InterfaceNotFound { name: String },
+    #[snafu(display("Invalid connection parameters: {}", source))]
+    ConfigError { source: omnixtend_rs::config::Error },
+
+    #[snafu(display("Runner Error: {}", source))]
+    RunnerError { source: omnixtend_rs::runner::Error },
 
     #[snafu(display("Could not open file {}: {}", name, source))]
     InvalidFileError {
@@ -58,9 +66,6 @@ pub enum Error {
     #[snafu(display("CTRL-C Error: {}", source))]
     CTRLCError { source: ctrlc::Error },
 
-    #[snafu(display("Unhandled channel type."))]
-    UnhandledChannelType {},
-
     #[snafu(display("Invalid MAC address: {}", source))]
     InvalidMac { source: ParseMacAddrErr },
 
@@ -76,56 +81,57 @@ pub enum Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Chunks fail with an error instead of hanging forever if the endpoint stops responding.
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The transfer is aborted once the endpoint stopped responding, e.g., after a pulled cable.
+const LIVENESS: Liveness = Liveness {
+    max_resends: 20,
+    timeout: Duration::from_secs(3),
+};
+
 fn run(opts: &Opts) -> Result<()> {
     log_panics::init();
 
     println!("Using options {:?}", opts);
 
-    let interface_names_match = |iface: &NetworkInterface| iface.name == opts.interface;
-
-    let interfaces = datalink::interfaces();
-    let interface =
-        interfaces
-            .into_iter()
-            .find(interface_names_match)
-            .ok_or(Error::InterfaceNotFound {
-                name: opts.interface.to_string(),
-            })?;
-
-    // Create a new channel, dealing with layer 2 packets
-    let (tx, rx) = setup_connection(interface)?;
+    let transport = setup_transport(opts)?;
 
     let my_mac = MacAddr::from_str(&opts.my_mac).context(InvalidMacSnafu)?;
     let other_mac = MacAddr::from_str(&opts.other_mac).context(InvalidMacSnafu)?;
 
-    let ctrl_c_pressed_action = setup_ctrlc()?;
+    let config = connection_config(opts, transport.as_ref())?;
 
-    let (connection, cache, operations) = create_ox_handling(opts, my_mac, other_mac);
+    let ctrl_c_pressed_action = setup_ctrlc()?;
 
-    let connection_local = connection.clone();
-    let cache_local = cache.clone();
-    let operations_local = operations.clone();
-    let rx_thread = thread::spawn(move || {
-        rx_thread_handler(rx, connection_local, cache_local, operations_local);
-    });
+    let (connection, cache, operations) = create_ox_handling(opts, &config, my_mac, other_mac);
 
-    let connection_local = connection.clone();
-    let cache_local = cache.clone();
-    let operations_local = operations.clone();
-    let tick_thread = thread::spawn(move || {
-        tick_thread_handler(operations_local, connection_local, cache_local);
-    });
+    let runner = Runner::new(
+        transport,
+        Arc::new(Requester::new(
+            connection.clone(),
+            cache,
+            operations.clone(),
+            Tick::with_config(&config),
+        )),
+    );
 
-    let connection_local = connection.clone();
-    let tx_thread = thread::spawn(move || {
-        tx_thread_handler(connection_local, tx);
+    let ctrl_c_pressed_failed = ctrl_c_pressed_action.clone();
+    connection.set_failure_handler(move || {
+        error!("Connection lost, the endpoint stopped responding. Aborting.");
+        ctrl_c_pressed_failed.store(true, Ordering::Relaxed);
     });
 
     let base_addr = opts.base_address;
     let filename = opts.file.clone();
-    let ifc = opts.interface.clone();
+    let ifc = match (&opts.interface, opts.udp_local, opts.udp_remote) {
+        (_, Some(local), Some(remote)) => format!("UDP {} -> {}", local, remote),
+        (Some(i), _, _) => i.clone(),
+        _ => String::new(),
+    };
     let is_read = opts.is_read;
     let size = opts.size;
+    let vc = opts.vc;
 
     let connection_local = connection.clone();
     let operations_local = operations.clone();
@@ -141,90 +147,62 @@ fn run(opts: &Opts) -> Result<()> {
             my_mac,
             operations_local,
             size,
+            vc,
         );
     });
 
     action_thread
         .join()
         .map_err(|x| Error::ThreadError { s: x })?;
-    tick_thread
-        .join()
-        .map_err(|x| Error::ThreadError { s: x })?;
-    tx_thread.join().map_err(|x| Error::ThreadError { s: x })?;
-    rx_thread.join().map_err(|x| Error::ThreadError { s: x })?;
+    runner.join().context(RunnerSnafu)?;
 
     Ok(())
 }
 
-fn tx_thread_handler(connection_local: Arc<Connection>, mut tx: Box<dyn DataLinkSender>) {
-    loop {
-        while let Some(x) = connection_local.get_packet() {
-            tx.send_to(&x[..], None);
-        }
-
-        if connection_local.connection_state() == ConnectionState::Idle {
-            break;
+fn setup_transport(opts: &Opts) -> Result<Arc<dyn Transport>> {
+    Ok(match (&opts.interface, opts.udp_local, opts.udp_remote) {
+        (_, Some(local), Some(remote)) => {
+            Arc::new(UdpTransport::new(local, remote).context(TransportSnafu)?)
         }
-        std::thread::yield_now();
-    }
-    info!("TX Thread done.");
-}
-
-fn tick_thread_handler(
-    operations_local: Arc<Operations>,
-    connection_local: Arc<Connection>,
-    cache_local: Arc<Cache>,
-) {
-    let mut tick = Tick::new(
-        Duration::from_millis(1),
-        Duration::from_millis(100),
-        Duration::from_micros(1),
-        Some(Duration::from_secs(1)),
-    );
-    loop {
-        tick.tick(&operations_local, &connection_local, &cache_local);
-
-        if connection_local.connection_state() == ConnectionState::Idle {
-            break;
-        }
-        std::thread::yield_now();
-    }
-    info!("Tick Thread done.");
+        (Some(i), _, _) => Arc::new(DatalinkTransport::new(i).context(TransportSnafu)?),
+        _ => Err(Error::NoTransport {})?,
+    })
 }
 
-fn rx_thread_handler(
-    mut rx: Box<dyn DataLinkReceiver>,
-    connection_local: Arc<Connection>,
-    cache_local: Arc<Cache>,
-    operations_local: Arc<Operations>,
-) {
-    loop {
-        if let Ok(packet) = rx.next().context(IOSnafu) {
-            trace!("Packet in ...");
-            if let Err(e) =
-                process_packet(packet, &connection_local, &cache_local, &operations_local)
-            {
-                trace!("Failed parsing packet: {}", e);
-            }
-        }
-        if connection_local.connection_state() == ConnectionState::Idle {
-            break;
-        }
-        std::thread::yield_now();
+fn connection_config(opts: &Opts, transport: &dyn Transport) -> Result<ConnectionConfig> {
+    let config = ConnectionConfig::default()
+        .with_compat_mode(opts.ox10_mode)
+        .with_liveness(LIVENESS);
+    match opts.mtu {
+        Some(mtu) => config.with_mtu(mtu),
+        None if opts.standard_frames => Ok(config.with_standard_frames()),
+        None => config.with_transport_mtu(transport),
     }
-    info!("RX Thread done.");
+    .and_then(|c| c.with_max_messages_per_frame(opts.max_tl_per_frame))
+    .and_then(|c| match opts.vlan {
+        Some(id) => c.with_vlan(id, opts.vlan_pcp),
+        None => Ok(c),
+    })
+    .and_then(|c| c.with_virtual_channels(opts.vc as usize + 1))
+    .context(ConfigSnafu)
 }
 
 fn create_ox_handling(
     opts: &Opts,
+    config: &ConnectionConfig,
     my_mac: MacAddr,
     other_mac: MacAddr,
 ) -> (Arc<Connection>, Arc<Cache>, Arc<Operations>) {
-    let connection = Arc::new(Connection::new(opts.ox10_mode, 0, my_mac, other_mac));
+    let connection = Arc::new(if opts.udp_remote.is_some() {
+        Connection::udp_with_config(config.clone(), 0)
+    } else {
+        Connection::with_config(config.clone(), 0, my_mac, other_mac)
+    });
     connection.establish_connection();
     thread::sleep(Duration::from_millis(100));
     let cache = Arc::new(Cache::new(0));
-    let operations = Arc::new(Operations::new());
+    let operations = Arc::new(Operations::with_config(config));
+    operations.set_timeout(Some(OPERATION_TIMEOUT));
     (connection, cache, operations)
 }
 
@@ -239,23 +217,6 @@ fn setup_ctrlc() -> Result<Arc<AtomicBool>> {
     Ok(ctrl_c_pressed_action)
 }
 
-fn setup_connection(
-    interface: NetworkInterface,
-) -> Result<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)> {
-    let config = pnet::datalink::Config {
-        write_buffer_size: 16384 * 16,
-        read_buffer_size: 16384 * 16,
-        read_timeout: Some(Duration::from_secs(1)),
-        ..Default::default()
-    };
-
-    Ok(match datalink::channel(&interface, config) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => return Err(Error::UnhandledChannelType {}),
-        Err(e) => return Err(e).context(IOSnafu)?,
-    })
-}
-
 fn execution_thread(
     connection_local: Arc<Connection>,
     ctrl_c_pressed_action: Arc<AtomicBool>,
@@ -267,6 +228,7 @@ fn execution_thread(
     my_mac: MacAddr,
     operations_local: Arc<Operations>,
     size: u64,
+    vc: u8,
 ) {
     println!("Hello from execution thread...");
     println!("Waiting for connection");
@@ -314,6 +276,7 @@ fn execution_thread(
                 base_addr,
                 &operations_local,
                 &connection_local,
+                vc,
             );
             println!(
                 "Done in {:#?}. ({}/s).",
@@ -342,6 +305,7 @@ fn execution_thread(
                 base_addr,
                 &connection_local,
                 ctrl_c_pressed_action,
+                vc,
             );
             println!(
                 "Done in {:#?}. ({}/s).",
@@ -372,7 +336,11 @@ fn do_parallel_read(
     base_addr: u64,
     connection_local: &Arc<Connection>,
     ctrl_c_pressed_action: Arc<AtomicBool>,
+    vc: u8,
 ) -> Vec<u8> {
+    let credits = connection_local
+        .vc_credits(vc)
+        .expect("Connection is configured with the virtual channel.");
     let mut buf = vec![0xFF; size as usize];
 
     buf.par_chunks_mut(chunk_size)
@@ -389,7 +357,7 @@ fn do_parallel_read(
                     address: addr,
                     len_bytes: chunk_size,
                 }),
-                connection_local.credits(),
+                credits,
             ) {
                 Ok(TLResult::Data(v)) => v,
                 Err(e) => {
@@ -415,7 +383,11 @@ fn do_parallel_write(
     base_addr: u64,
     operations_local: &Arc<Operations>,
     connection_local: &Arc<Connection>,
+    vc: u8,
 ) {
+    let credits = connection_local
+        .vc_credits(vc)
+        .expect("Connection is configured with the virtual channel.");
     mmap[..]
         .par_chunks(chunk_size)
         .enumerate()
@@ -432,7 +404,7 @@ fn do_parallel_write(
                         address: addr,
                         data: c,
                     }),
-                    connection_local.credits(),
+                    credits,
                 ) {
                     error!("Failed write to 0x{:X}: {}", addr, e);
                 }
@@ -441,7 +413,7 @@ fn do_parallel_write(
                     address: addr,
                     data: c,
                 }),
-                connection_local.credits(),
+                credits,
             ) {
                 error!("Failed partial write to 0x{:X}: {}", addr, e);
             }
@@ -451,8 +423,12 @@ fn do_parallel_write(
 #[derive(Debug, Parser)]
 #[clap(author = "Jaco Hofmann <Jaco.Hofmann@wdc.com>")]
 struct Opts {
-    #[clap(short, long)]
-    interface: String,
+    #[clap(short, long, required_unless_present = "udp_remote")]
+    interface: Option<String>,
+    #[clap(long, requires = "udp_remote")]
+    udp_local: Option<SocketAddr>,
+    #[clap(long, requires = "udp_local")]
+    udp_remote: Option<SocketAddr>,
     #[clap(short, long, default_value = "00:00:00:00:00:01")]
     my_mac: String,
     #[clap(short, long, default_value = "00:00:00:00:00:00")]
@@ -463,6 +439,26 @@ struct Opts {
     base_address: u64,
     #[clap(long)]
     ox10_mode: bool,
+    /// Maximum frame size in bytes, has to match MAXIMUM_PACKET_SIZE of the endpoint. Defaults
+    /// to 9000 or less if the interface MTU is smaller
+    #[clap(long)]
+    mtu: Option<usize>,
+    /// Use standard 1500 byte frames, e.g., for switches without jumbo frame support
+    #[clap(long, conflicts_with = "mtu")]
+    standard_frames: bool,
+    /// Maximum number of TileLink messages per frame, see MAXIMUM_TL_PER_FRAME
+    #[clap(long, default_value = "64")]
+    max_tl_per_frame: usize,
+    /// Tag frames with this 802.1Q VLAN ID
+    #[clap(long)]
+    vlan: Option<u16>,
+    /// 802.1Q priority of tagged frames
+    #[clap(long, default_value = "0", requires = "vlan")]
+    vlan_pcp: u8,
+    /// Virtual channel used for the transfer, the endpoint has to support it. Lost frames and
+    /// missing credits of the transfer do not hold back VC 0
+    #[clap(long, default_value = "0")]
+    vc: u8,
     #[clap(long)]
     is_read: bool,
     #[clap(long, default_value = "0")]
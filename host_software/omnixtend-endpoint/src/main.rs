@@ -0,0 +1,171 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+#[macro_use]
+extern crate log;
+
+use clap::Parser;
+use omnixtend_rs::config::ConnectionConfig;
+use omnixtend_rs::endpoint::Endpoint;
+use omnixtend_rs::responder::Memory;
+use omnixtend_rs::runner::Runner;
+use omnixtend_rs::transport::{DatalinkTransport, Transport, UdpTransport};
+use pnet::util::{MacAddr, ParseMacAddrErr};
+use snafu::prelude::*;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Transport Error: {}", source))]
+    TransportError {
+        source: omnixtend_rs::transport::Error,
+    },
+
+    #[snafu(display("Either an interface or UDP addresses are required."))]
+    NoTransport {},
+
+    #[snafu(display("CTRL-C Error: {}", source))]
+    CTRLCError { source: ctrlc::Error },
+
+    #[snafu(display("Invalid MAC address: {}", source))]
+    InvalidMac { source: ParseMacAddrErr },
+
+    #[snafu(display("Invalid connection parameters: {}", source))]
+    ConfigError { source: omnixtend_rs::config::Error },
+
+    #[snafu(display("Runner Error: {}", source))]
+    RunnerError { source: omnixtend_rs::runner::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+fn run(opts: &Opts) -> Result<()> {
+    println!("Using options {:?}", opts);
+
+    let my_mac = MacAddr::from_str(&opts.mac).context(InvalidMacSnafu)?;
+
+    let ctrl_c_pressed = setup_ctrlc()?;
+
+    let memory = Arc::new(Memory::new(opts.size));
+
+    let transport: Arc<dyn Transport> = match (&opts.interface, opts.udp_local, opts.udp_remote) {
+        (_, Some(local), Some(remote)) => {
+            println!(
+                "Serving {} bytes via UDP on {} for {}.",
+                opts.size, local, remote
+            );
+            Arc::new(UdpTransport::new(local, remote).context(TransportSnafu)?)
+        }
+        (Some(i), _, _) => {
+            println!(
+                "Serving {} bytes on interface {} with mac {}.",
+                opts.size, i, my_mac
+            );
+            Arc::new(DatalinkTransport::new(i).context(TransportSnafu)?)
+        }
+        _ => Err(Error::NoTransport {})?,
+    };
+
+    let config = ConnectionConfig::default().with_compat_mode(opts.ox10_mode);
+    let config = match opts.mtu {
+        Some(mtu) => config.with_mtu(mtu),
+        None if opts.standard_frames => Ok(config.with_standard_frames()),
+        None => config.with_transport_mtu(transport.as_ref()),
+    }
+    .and_then(|c| c.with_max_messages_per_frame(opts.max_tl_per_frame))
+    .and_then(|c| match opts.vlan {
+        Some(id) => c.with_vlan(id, opts.vlan_pcp),
+        None => Ok(c),
+    })
+    .and_then(|c| c.with_virtual_channels(opts.virtual_channels))
+    .context(ConfigSnafu)?;
+    println!("Using frames of up to {} bytes.", config.mtu());
+
+    let endpoint = Arc::new(if opts.udp_remote.is_some() {
+        Endpoint::udp_with_config(config, memory)
+    } else {
+        Endpoint::with_config(config, my_mac, memory)
+    });
+
+    let runner = Runner::new(transport, endpoint);
+
+    while !ctrl_c_pressed.load(Ordering::Relaxed) && !runner.is_finished() {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    runner.stop();
+    runner.join().context(RunnerSnafu)?;
+
+    println!("Endpoint stopped, good bye.");
+
+    Ok(())
+}
+
+fn setup_ctrlc() -> Result<Arc<AtomicBool>> {
+    let ctrl_c_pressed = Arc::new(AtomicBool::new(false));
+    let ctrl_c_pressed_action = ctrl_c_pressed.clone();
+    ctrlc::set_handler(move || {
+        info!("Ctrl-C pressed.");
+        ctrl_c_pressed.store(true, Ordering::Relaxed);
+    })
+    .context(CTRLCSnafu)?;
+    Ok(ctrl_c_pressed_action)
+}
+
+#[derive(Debug, Parser)]
+#[clap(author = "Jaco Hofmann <Jaco.Hofmann@wdc.com>")]
+struct Opts {
+    #[clap(short, long, required_unless_present = "udp_remote")]
+    interface: Option<String>,
+    #[clap(long, requires = "udp_remote")]
+    udp_local: Option<SocketAddr>,
+    #[clap(long, requires = "udp_local")]
+    udp_remote: Option<SocketAddr>,
+    #[clap(short, long, default_value = "00:00:00:00:00:00")]
+    mac: String,
+    #[clap(short, long, default_value = "8589934592")]
+    size: u64,
+    #[clap(long)]
+    ox10_mode: bool,
+    /// Maximum frame size in bytes, matches MAXIMUM_PACKET_SIZE of the hardware endpoint.
+    /// Defaults to 9000 or less if the interface MTU is smaller
+    #[clap(long)]
+    mtu: Option<usize>,
+    /// Use standard 1500 byte frames, e.g., for switches without jumbo frame support
+    #[clap(long, conflicts_with = "mtu")]
+    standard_frames: bool,
+    /// Maximum number of TileLink messages per frame, see MAXIMUM_TL_PER_FRAME
+    #[clap(long, default_value = "64")]
+    max_tl_per_frame: usize,
+    /// Tag frames with this 802.1Q VLAN ID
+    #[clap(long)]
+    vlan: Option<u16>,
+    /// 802.1Q priority of tagged frames
+    #[clap(long, default_value = "0", requires = "vlan")]
+    vlan_pcp: u8,
+    /// Number of virtual channels per connection, e.g., 2 for bitload transfers with --vc 1
+    #[clap(long, default_value = "1")]
+    virtual_channels: usize,
+}
+
+fn main() {
+    env_logger::init();
+
+    let opts: Opts = Opts::parse();
+
+    match run(&opts) {
+        Ok(_) => (),
+        Err(e) => error!("ERROR: {:?}", e),
+    }
+}
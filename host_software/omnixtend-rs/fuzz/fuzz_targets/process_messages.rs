@@ -0,0 +1,18 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use omnixtend_rs::channels::Channel;
+
+// Parses the input as payload of a packet in the requester and the responder role.
+fuzz_target!(|data: &[u8]| {
+    let _ = Channel::process_messages(data);
+    let _ = Channel::process_requests(data);
+});
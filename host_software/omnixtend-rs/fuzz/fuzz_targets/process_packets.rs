@@ -0,0 +1,63 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use omnixtend_rs::{
+    cache::Cache,
+    connection::Connection,
+    credits::Credits,
+    operations::{ArithmeticOp, Operations, PermOp, ReadOp, ReadOpLen, TLOperations},
+    tilelink_messages::{OmnixtendArithmetic, OmnixtendPermissionChangeGrow},
+    utils::process_packet,
+};
+use pnet::util::MacAddr;
+
+// The first byte selects the encapsulation, the rest is handed to a fresh connection as received
+// frame. Sources 0 to 3 are waiting for a response, so responses reach the completion path. Errors
+// are fine, panics are not.
+fuzz_target!(|data: &[u8]| {
+    let Some((mode, frame)) = data.split_first() else {
+        return;
+    };
+    let connection = if mode & 1 == 0 {
+        Connection::new(false, 0, MacAddr::zero(), MacAddr::broadcast())
+    } else {
+        Connection::new_udp(mode & 2 != 0, 0)
+    };
+    let cache = Cache::new(0);
+    let operations = Operations::new();
+    // Compat mode starts without send credits
+    let credits = Credits::new(16);
+    let operand = [1; 8];
+    for op in [
+        TLOperations::Read(ReadOp { address: 0 }),
+        TLOperations::AcquireBlock(PermOp {
+            address: 0x40,
+            len: 64,
+            permissions: OmnixtendPermissionChangeGrow::NtoT,
+        }),
+        TLOperations::ReadLen(ReadOpLen {
+            address: 0x80,
+            len_bytes: 64,
+        }),
+        TLOperations::Arithmetic(ArithmeticOp {
+            address: 0xC0,
+            data: &operand,
+            param: OmnixtendArithmetic::Max,
+        }),
+    ] {
+        operations
+            .try_submit(&op, &credits)
+            .expect("Failed to submit operation.")
+            .expect("No source or credits for operation.");
+    }
+
+    let _ = process_packet(frame, &connection, &cache, &operations);
+});
@@ -0,0 +1,187 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use crate::{
+    credits::Credits,
+    operations::{
+        Completion, Operations, PermOp, ReadOp, ReadOpLen, ReleaseDataOp, ReleaseOp, Result,
+        TLOperations, TLResult, WriteOp, WriteOpLen,
+    },
+    tilelink_messages::OmnixtendPermissionChangeGrow,
+};
+
+/// Identifies the `Submit` futures, each keeps a single waker registered at a time.
+static NEXT_SUBMIT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Future returned by `Operations::submit_async`. Resolves once the operation has been queued for
+/// sending, i.e., a source and enough credits were available. Does not depend on a specific
+/// executor, the task is woken when sources are freed or credits are returned.
+pub struct Submit<'a> {
+    operations: &'a Operations,
+    operation: &'a TLOperations<'a>,
+    credits: &'a Credits,
+    id: usize,
+}
+
+impl Future for Submit<'_> {
+    type Output = Result<Completion>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(c) = self.operations.try_submit(self.operation, self.credits)? {
+            return Poll::Ready(Ok(c));
+        }
+
+        self.operations.register_source_waker(self.id, cx.waker());
+        self.credits.register_waker(self.id, cx.waker());
+
+        // Sources or credits might have been returned before the wakers were registered
+        match self.operations.try_submit(self.operation, self.credits)? {
+            Some(c) => Poll::Ready(Ok(c)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Submit<'_> {
+    fn drop(&mut self) {
+        self.operations.remove_source_waker(self.id);
+        self.credits.remove_waker(self.id);
+    }
+}
+
+/// Async counterparts of `Operations::perform`. The futures complete when the response is passed
+/// to `Operations::complete` by the packet processing, so no thread is blocked while waiting.
+impl Operations {
+    pub fn submit_async<'a>(
+        &'a self,
+        operation: &'a TLOperations<'a>,
+        credits: &'a Credits,
+    ) -> Submit<'a> {
+        Submit {
+            operations: self,
+            operation,
+            credits,
+            id: NEXT_SUBMIT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub async fn perform_async(
+        &self,
+        operation: &TLOperations<'_>,
+        credits: &Credits,
+    ) -> Result<TLResult> {
+        let ret = self.submit_async(operation, credits).await?.await;
+        self.process_responses(credits);
+        ret
+    }
+
+    pub async fn read(&self, credits: &Credits, address: u64) -> Result<u64> {
+        self.perform_async(&TLOperations::Read(ReadOp { address }), credits)
+            .await
+            .map(|r| r.get_data64())
+    }
+
+    pub async fn write(&self, credits: &Credits, address: u64, data: u64) -> Result<()> {
+        self.perform_async(&TLOperations::Write(WriteOp { address, data }), credits)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn read_len(&self, credits: &Credits, address: u64, len: usize) -> Result<Vec<u8>> {
+        self.perform_async(
+            &TLOperations::ReadLen(ReadOpLen {
+                address,
+                len_bytes: len,
+            }),
+            credits,
+        )
+        .await
+        .map(|r| r.get_data())
+    }
+
+    /// Writes `data` to `address`, split into several writes if it does not fit into a frame.
+    pub async fn write_len(&self, credits: &Credits, address: u64, data: &[u8]) -> Result<()> {
+        let operation = TLOperations::WriteLen(WriteOpLen { address, data });
+        match self.split_write(&operation) {
+            Some(parts) => {
+                for p in parts {
+                    self.perform_async(&p, credits).await?;
+                }
+                Ok(())
+            }
+            None => self.perform_async(&operation, credits).await.map(|_| ()),
+        }
+    }
+
+    /// Acquires the block at `address` and returns its data. The GrantAck is sent before the
+    /// future completes, if credits allow, otherwise by the next tick.
+    pub async fn acquire_block(
+        &self,
+        credits: &Credits,
+        address: u64,
+        len: usize,
+        permissions: OmnixtendPermissionChangeGrow,
+    ) -> Result<Vec<u8>> {
+        self.perform_async(
+            &TLOperations::AcquireBlock(PermOp {
+                address,
+                len,
+                permissions,
+            }),
+            credits,
+        )
+        .await
+        .map(|r| r.get_data())
+    }
+
+    pub async fn acquire_perm(
+        &self,
+        credits: &Credits,
+        address: u64,
+        len: usize,
+        permissions: OmnixtendPermissionChangeGrow,
+    ) -> Result<()> {
+        self.perform_async(
+            &TLOperations::AcquirePerm(PermOp {
+                address,
+                len,
+                permissions,
+            }),
+            credits,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn release(&self, credits: &Credits, release: ReleaseOp) -> Result<()> {
+        self.perform_async(&TLOperations::Release(release), credits)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn release_data(
+        &self,
+        credits: &Credits,
+        release: ReleaseOp,
+        data: &[u8],
+    ) -> Result<()> {
+        self.perform_async(
+            &TLOperations::ReleaseData(ReleaseDataOp { release, data }),
+            credits,
+        )
+        .await
+        .map(|_| ())
+    }
+}
@@ -7,18 +7,28 @@
 */
 
 use dashmap::DashMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 use snafu::ResultExt;
-use std::{convert::TryInto, thread};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    ops::AddAssign,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
+    coherence::CoherenceChecker,
     credits::Credits,
     operations::{
-        Operations, PermOp, ProbeDataOp, ProbeOp, ReleaseDataOp, ReleaseOp, TLOperations, TLResult,
+        Completion, Operations, PermOp, ProbeDataOp, ProbeOp, ReleaseDataOp, ReleaseOp,
+        TLOperations,
     },
     tilelink_messages::{
-        get_permission_change, get_permission_change_grow, ChanABCDTilelinkMessage,
-        OmnixtendPermissionChangeCap,
+        get_permission_change, get_permission_change_grow, get_resulting_permission,
+        ChanABCDTilelinkMessage, OmnixtendPermissionChangeCap, OmnixtendPermissionChangeGrow,
     },
 };
 
@@ -36,45 +46,662 @@ pub enum Error {
 
     #[snafu(display("Address not in cache: {:#16X}", addr))]
     NotInCache { addr: u64 },
+
+    #[snafu(display("Address is not aligned to 8 bytes: {:#16X}", addr))]
+    Unaligned { addr: u64 },
+
+    #[snafu(display(
+        "Cache line size has to be a power of two between 8 and 32768 bytes: {}",
+        size
+    ))]
+    InvalidLineSize { size: usize },
+
+    #[snafu(display("Cache capacity has to hold at least one line."))]
+    InvalidCapacity {},
+
+    #[snafu(display("Pinning and prefetching requires Branch or Trunk permissions."))]
+    InvalidPermissions {},
+
+    #[snafu(display(
+        "Cannot change permissions of {:#16X} from {:?} to {:?}.",
+        addr,
+        from,
+        to
+    ))]
+    InvalidDowngrade {
+        addr: u64,
+        from: OmnixtendPermissionChangeCap,
+        to: OmnixtendPermissionChangeCap,
+    },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub type Probe = (ChanABCDTilelinkMessage, u64);
 
+/// Line size used by `Cache::new`, a single 64 bit word.
+pub const DEFAULT_LINE_SIZE: usize = 8;
+
+/// Snapshot of the counters of a `Cache`, see `Cache::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Accesses that missed while requiring Branch permissions, i.e., reads.
+    pub misses_branch: u64,
+    /// Accesses that missed while requiring Trunk permissions, i.e., writes.
+    pub misses_trunk: u64,
+    pub acquires: u64,
+    pub probes_to_t: u64,
+    pub probes_to_b: u64,
+    pub probes_to_n: u64,
+    /// ProbeAckData messages carrying dirty data.
+    pub probe_writebacks: u64,
+    pub releases: u64,
+    /// ReleaseData messages carrying dirty data.
+    pub release_writebacks: u64,
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    pub fn misses(&self) -> u64 {
+        self.misses_branch + self.misses_trunk
+    }
+}
+
+impl AddAssign for CacheStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.hits += rhs.hits;
+        self.misses_branch += rhs.misses_branch;
+        self.misses_trunk += rhs.misses_trunk;
+        self.acquires += rhs.acquires;
+        self.probes_to_t += rhs.probes_to_t;
+        self.probes_to_b += rhs.probes_to_b;
+        self.probes_to_n += rhs.probes_to_n;
+        self.probe_writebacks += rhs.probe_writebacks;
+        self.releases += rhs.releases;
+        self.release_writebacks += rhs.release_writebacks;
+        self.evictions += rhs.evictions;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses_branch: AtomicU64,
+    misses_trunk: AtomicU64,
+    acquires: AtomicU64,
+    probes_to_t: AtomicU64,
+    probes_to_b: AtomicU64,
+    probes_to_n: AtomicU64,
+    probe_writebacks: AtomicU64,
+    releases: AtomicU64,
+    release_writebacks: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Counters {
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn read(&self, f: impl Fn(&AtomicU64) -> u64) -> CacheStats {
+        CacheStats {
+            hits: f(&self.hits),
+            misses_branch: f(&self.misses_branch),
+            misses_trunk: f(&self.misses_trunk),
+            acquires: f(&self.acquires),
+            probes_to_t: f(&self.probes_to_t),
+            probes_to_b: f(&self.probes_to_b),
+            probes_to_n: f(&self.probes_to_n),
+            probe_writebacks: f(&self.probe_writebacks),
+            releases: f(&self.releases),
+            release_writebacks: f(&self.release_writebacks),
+            evictions: f(&self.evictions),
+        }
+    }
+
+    fn snapshot(&self) -> CacheStats {
+        self.read(|c| c.load(Ordering::Relaxed))
+    }
+
+    fn reset(&self) -> CacheStats {
+        self.read(|c| c.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// State of a cache line. Any state but `Stable` means that a transaction for the line is in
+/// flight. Accesses to such a line wait until the transaction has finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineState {
+    Stable,
+    Acquiring,
+    Releasing,
+    Probing,
+}
+
 #[derive(Debug, Clone)]
 pub struct CachedEntry {
     pub data: Vec<u8>,
     pub modified: bool,
     pub valid: bool,
-    pub release_pending: bool,
+    pub state: LineState,
     pub permissions: OmnixtendPermissionChangeCap,
+    /// Value of the access counter of the cache when the line was last used, the line with the
+    /// lowest value is evicted first.
+    pub last_access: u64,
+}
+
+/// Miss status holding register, tracks the transaction in flight for a line. Accesses to the
+/// line wait for it instead of issuing their own transaction.
+struct Mshr {
+    state: LineState,
+    /// ProbeAck of a line in `Probing`, resent unchanged if sending it failed.
+    probe: Option<ProbeResponse>,
+    done: Mutex<bool>,
+    finished: Condvar,
+}
+
+impl Mshr {
+    fn new(state: LineState) -> Arc<Mshr> {
+        Arc::new(Mshr {
+            state,
+            probe: None,
+            done: Mutex::new(false),
+            finished: Condvar::new(),
+        })
+    }
+
+    fn probing(probe: ProbeResponse) -> Arc<Mshr> {
+        Arc::new(Mshr {
+            state: LineState::Probing,
+            probe: Some(probe),
+            done: Mutex::new(false),
+            finished: Condvar::new(),
+        })
+    }
+
+    fn wait(&self) {
+        let mut done = self.done.lock();
+        while !*done {
+            self.finished.wait(&mut done);
+        }
+    }
+
+    fn finish(&self) {
+        *self.done.lock() = true;
+        self.finished.notify_all();
+    }
+}
+
+/// ProbeAck for a line. The line keeps its permissions and data until the ProbeAck has been sent.
+#[derive(Clone)]
+struct ProbeResponse {
+    permission_change: u8,
+    permissions: OmnixtendPermissionChangeCap,
+    writeback: Option<Vec<u8>>,
 }
 
+/// Outcome of `Cache::begin_acquire`.
+enum AcquireStart {
+    /// The line is already held with the required permissions.
+    Held,
+    /// Another transaction is in flight for the line.
+    InFlight(Arc<Mshr>),
+    /// An MSHR has been allocated, the Acquire from the given permissions has to be sent.
+    Started(Arc<Mshr>, OmnixtendPermissionChangeCap),
+}
+
+/// Acquire whose Grant is processed by `Cache::process_background` instead of a waiting thread.
+struct BackgroundAcquire {
+    line: u64,
+    permissions: OmnixtendPermissionChangeGrow,
+    mshr: Arc<Mshr>,
+    completion: Completion,
+}
+
+/// Coherent cache of a requester. Lines of `line_size` bytes are acquired as a whole and kept by
+/// their aligned address. Reads and writes of 64 bit words are served from the cached lines.
+///
+/// Each line with a transaction in flight has an MSHR. Concurrent misses on the same line are
+/// merged, only the first one sends an Acquire while the others sleep until the Grant has been
+/// processed. The MSHRs are only modified while holding the `mshrs` lock, which is always taken
+/// before any entry of the line map.
+///
+/// With a capacity set, the least recently used line is released before a new line is acquired
+/// into a full cache. Lines with a transaction in flight and pinned lines do not count as
+/// candidates, so the capacity might be exceeded temporarily if all lines are busy. Lines
+/// re-acquired by `process_background` do not evict other lines either.
+///
+/// Pinned lines are re-acquired in the background whenever a probe took their permissions.
+/// Prefetches use the same mechanism, their Grants are processed by `process_background`.
 pub struct Cache {
     cache: DashMap<u64, CachedEntry>,
+    mshrs: Mutex<HashMap<u64, Arc<Mshr>>>,
     id: u8,
+    line_size: usize,
+    capacity: Option<usize>,
+    access_cntr: AtomicU64,
     probes: Mutex<Vec<Probe>>,
+    checker: CoherenceChecker,
+    counters: Counters,
+    pinned: Mutex<HashMap<u64, OmnixtendPermissionChangeCap>>,
+    background: Mutex<Vec<BackgroundAcquire>>,
 }
 
 impl Cache {
     pub fn new(id: u8) -> Cache {
         Cache {
             cache: DashMap::new(),
+            mshrs: Mutex::new(HashMap::new()),
             id,
+            line_size: DEFAULT_LINE_SIZE,
+            capacity: None,
+            access_cntr: AtomicU64::new(0),
             probes: Mutex::new(Vec::new()),
+            checker: CoherenceChecker::default(),
+            counters: Counters::default(),
+            pinned: Mutex::new(HashMap::new()),
+            background: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn release(&self, operations: &Operations, credits: &Credits) -> Result<()> {
-        self.cache
-            .iter_mut()
-            .filter(|v| {
-                v.valid && !v.release_pending && v.permissions != OmnixtendPermissionChangeCap::ToN
+    /// Creates a cache with lines of `line_size` bytes, e.g., 64, 128 or 256 bytes to match the
+    /// burst size of the endpoint.
+    pub fn with_line_size(id: u8, line_size: usize) -> Result<Cache> {
+        if !line_size.is_power_of_two() || !(8..=1 << 15).contains(&line_size) {
+            Err(Error::InvalidLineSize { size: line_size })?;
+        }
+        Ok(Cache {
+            line_size,
+            ..Self::new(id)
+        })
+    }
+
+    /// Creates a cache holding at most `capacity` lines of `line_size` bytes.
+    pub fn with_capacity(id: u8, line_size: usize, capacity: usize) -> Result<Cache> {
+        if capacity == 0 {
+            Err(Error::InvalidCapacity {})?;
+        }
+        Ok(Cache {
+            capacity: Some(capacity),
+            ..Self::with_line_size(id, line_size)?
+        })
+    }
+
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    /// Maximum number of lines, `None` if the cache is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Number of lines currently held, including lines that are being released.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Checker validating the permission changes of this cache, disabled by default. Enable it
+    /// with `cache.checker().set_enabled(true)` and inspect `cache.checker().violations()`.
+    pub fn checker(&self) -> &CoherenceChecker {
+        &self.checker
+    }
+
+    /// Counters collected since the cache was created or `reset_stats` was called.
+    pub fn stats(&self) -> CacheStats {
+        self.counters.snapshot()
+    }
+
+    /// Returns the counters and sets them to zero.
+    pub fn reset_stats(&self) -> CacheStats {
+        self.counters.reset()
+    }
+
+    /// Number of lines with a transaction in flight.
+    pub fn num_in_flight(&self) -> usize {
+        self.mshrs.lock().len()
+    }
+
+    fn next_access(&self) -> u64 {
+        self.access_cntr.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn line_address(&self, address: u64) -> u64 {
+        address & !(self.line_size as u64 - 1)
+    }
+
+    /// Returns the line holding the word at `address` and the offset of the word in the line.
+    fn locate(&self, address: u64) -> Result<(u64, usize)> {
+        if address & 7 != 0 {
+            Err(Error::Unaligned { addr: address })?;
+        }
+        let line = self.line_address(address);
+        Ok((line, (address - line) as usize))
+    }
+
+    fn sufficient(
+        permissions: OmnixtendPermissionChangeCap,
+        required: OmnixtendPermissionChangeCap,
+    ) -> bool {
+        permissions == OmnixtendPermissionChangeCap::ToT || permissions == required
+    }
+
+    /// Calls `f` on the entry of `line` once the line is held with at least `permissions`.
+    fn access<T>(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        line: u64,
+        permissions: OmnixtendPermissionChangeCap,
+        f: impl FnOnce(&mut CachedEntry) -> T,
+    ) -> Result<T> {
+        let mut missed = false;
+        let mut entry = loop {
+            if let Some(entry) = self.cache.get_mut(&line) {
+                if entry.valid
+                    && entry.state == LineState::Stable
+                    && Self::sufficient(entry.permissions, permissions)
+                {
+                    break entry;
+                }
+            }
+            if !missed {
+                missed = true;
+                Counters::inc(if permissions == OmnixtendPermissionChangeCap::ToT {
+                    &self.counters.misses_trunk
+                } else {
+                    &self.counters.misses_branch
+                });
+            }
+            self.miss(operations, credits, line, permissions)?;
+        };
+        if !missed {
+            Counters::inc(&self.counters.hits);
+        }
+        entry.last_access = self.next_access();
+        Ok(f(&mut entry))
+    }
+
+    /// Handles a miss on `line`. Waits for the transaction in flight for the line, if there is
+    /// one, or acquires the line with `permissions` otherwise. The access has to be retried
+    /// afterwards as a probe might have taken the permissions again in the meantime.
+    fn miss(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        line: u64,
+        permissions: OmnixtendPermissionChangeCap,
+    ) -> Result<()> {
+        let (mshr, perm_cur) = match self.begin_acquire(line, permissions) {
+            AcquireStart::Held => return Ok(()),
+            AcquireStart::InFlight(mshr) => {
+                trace!(
+                    "Sim {}: CACHED_T Waiting for {:?} 0x{:X}",
+                    self.id,
+                    mshr.state,
+                    line
+                );
+                mshr.wait();
+                return Ok(());
+            }
+            AcquireStart::Started(mshr, perm_cur) => (mshr, perm_cur),
+        };
+
+        if perm_cur == OmnixtendPermissionChangeCap::ToN {
+            self.make_room(operations, credits);
+        }
+
+        let perm_change = self.acquire_permissions(line, perm_cur, permissions);
+        let ret = operations
+            .perform(&self.acquire_operation(line, perm_change), credits)
+            .context(OperationsSnafu);
+        match ret {
+            Ok(r) => {
+                self.finish_acquire(line, &mshr, Some((r.get_data(), perm_change)));
+                Ok(())
+            }
+            Err(e) => {
+                self.finish_acquire(line, &mshr, None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Allocates an MSHR for acquiring `line` with `permissions`, unless the line is held with
+    /// sufficient permissions already or another transaction is in flight.
+    fn begin_acquire(&self, line: u64, permissions: OmnixtendPermissionChangeCap) -> AcquireStart {
+        let mut mshrs = self.mshrs.lock();
+        if let Some(mshr) = mshrs.get(&line) {
+            return AcquireStart::InFlight(mshr.clone());
+        }
+
+        let perm_cur = match self.cache.get_mut(&line) {
+            Some(mut entry) => {
+                // The previous transaction might have finished after the lookup
+                if entry.valid && Self::sufficient(entry.permissions, permissions) {
+                    return AcquireStart::Held;
+                }
+                entry.state = LineState::Acquiring;
+                entry.permissions
+            }
+            None => OmnixtendPermissionChangeCap::ToN,
+        };
+        let mshr = Mshr::new(LineState::Acquiring);
+        mshrs.insert(line, mshr.clone());
+        AcquireStart::Started(mshr, perm_cur)
+    }
+
+    fn acquire_permissions(
+        &self,
+        line: u64,
+        perm_cur: OmnixtendPermissionChangeCap,
+        permissions: OmnixtendPermissionChangeCap,
+    ) -> OmnixtendPermissionChangeGrow {
+        let perm_change = get_permission_change_grow(&perm_cur, &permissions);
+        self.checker
+            .check_acquire(line, perm_cur, permissions, perm_change);
+        Counters::inc(&self.counters.acquires);
+        trace!(
+            "Sim {}: CACHED_T Requesting change for 0x{:X} -> {:?}",
+            self.id,
+            line,
+            perm_change
+        );
+        perm_change
+    }
+
+    fn acquire_operation(
+        &self,
+        line: u64,
+        permissions: OmnixtendPermissionChangeGrow,
+    ) -> TLOperations<'static> {
+        TLOperations::AcquireBlock(PermOp {
+            address: line,
+            len: self.line_size,
+            permissions,
+        })
+    }
+
+    /// Stores the granted data, or restores the line if the Acquire failed, and wakes all threads
+    /// waiting for the line.
+    fn finish_acquire(
+        &self,
+        line: u64,
+        mshr: &Mshr,
+        granted: Option<(Vec<u8>, OmnixtendPermissionChangeGrow)>,
+    ) {
+        {
+            let mut mshrs = self.mshrs.lock();
+            match granted {
+                Some((data, perm_change)) => {
+                    self.insert_entry(line, data, get_resulting_permission(&perm_change))
+                }
+                None => {
+                    if let Some(mut entry) = self.cache.get_mut(&line) {
+                        entry.state = LineState::Stable;
+                    }
+                }
+            }
+            mshrs.remove(&line);
+        }
+        mshr.finish();
+    }
+
+    /// Sends an Acquire for `line` without waiting for the Grant. With `block` unset, nothing is
+    /// sent if no source or not enough credits are available.
+    fn acquire_background(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        line: u64,
+        permissions: OmnixtendPermissionChangeCap,
+        block: bool,
+    ) -> Result<()> {
+        let (mshr, perm_cur) = match self.begin_acquire(line, permissions) {
+            AcquireStart::Started(mshr, perm_cur) => (mshr, perm_cur),
+            _ => return Ok(()),
+        };
+
+        let perm_change = get_permission_change_grow(&perm_cur, &permissions);
+        let operation = self.acquire_operation(line, perm_change);
+        let completion = if block {
+            operations.submit(&operation, credits).map(Some)
+        } else {
+            operations.try_submit(&operation, credits)
+        };
+        match completion.context(OperationsSnafu) {
+            Ok(Some(completion)) => {
+                self.acquire_permissions(line, perm_cur, permissions);
+                self.background.lock().push(BackgroundAcquire {
+                    line,
+                    permissions: perm_change,
+                    mshr,
+                    completion,
+                });
+                Ok(())
+            }
+            Ok(None) => {
+                self.finish_acquire(line, &mshr, None);
+                Ok(())
+            }
+            Err(e) => {
+                self.finish_acquire(line, &mshr, None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Processes the Grants of background acquires and re-acquires pinned lines that lost their
+    /// permissions to a probe. Never waits for a Grant, sources or credits, so it can be called
+    /// by the thread sending the packets, e.g., from `Tick::tick`.
+    pub fn process_background(&self, operations: &Operations, credits: &Credits) {
+        let done: Vec<BackgroundAcquire> = {
+            let mut background = self.background.lock();
+            let (done, pending) = std::mem::take(&mut *background)
+                .into_iter()
+                .partition(|b| b.completion.is_complete());
+            *background = pending;
+            done
+        };
+        for b in done {
+            match b.completion.poll() {
+                Some(Ok(r)) => {
+                    self.finish_acquire(b.line, &b.mshr, Some((r.get_data(), b.permissions)))
+                }
+                Some(Err(e)) => {
+                    error!("Failed to acquire 0x{:X} in background: {}", b.line, e);
+                    self.finish_acquire(b.line, &b.mshr, None);
+                }
+                None => self.finish_acquire(b.line, &b.mshr, None),
+            }
+        }
+
+        if operations.is_lost() {
+            return;
+        }
+
+        let pinned: Vec<(u64, OmnixtendPermissionChangeCap)> =
+            self.pinned.lock().iter().map(|(l, p)| (*l, *p)).collect();
+        for (line, permissions) in pinned {
+            if let Err(e) = self.acquire_background(operations, credits, line, permissions, false) {
+                error!("Failed to re-acquire pinned line 0x{:X}: {}", line, e);
+            }
+        }
+    }
+
+    /// Acquires the line holding `address` with `permissions` and keeps it cached. Pinned lines
+    /// are never evicted. A probe is still answered, but the line is acquired again afterwards.
+    pub fn pin(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        permissions: OmnixtendPermissionChangeCap,
+    ) -> Result<()> {
+        if permissions == OmnixtendPermissionChangeCap::ToN {
+            Err(Error::InvalidPermissions {})?;
+        }
+        let line = self.line_address(address);
+        self.pinned.lock().insert(line, permissions);
+        self.access(operations, credits, line, permissions, |_| ())
+            .inspect_err(|_| {
+                self.pinned.lock().remove(&line);
             })
-            .for_each(|mut v| {
-                self.handle_release(*v.key(), v.value_mut(), operations, credits);
-            });
+    }
+
+    /// Removes the pin of the line holding `address`, the line stays cached until evicted.
+    /// Returns `false` if the line was not pinned.
+    pub fn unpin(&self, address: u64) -> bool {
+        self.pinned
+            .lock()
+            .remove(&self.line_address(address))
+            .is_some()
+    }
+
+    pub fn is_pinned(&self, address: u64) -> bool {
+        self.pinned.lock().contains_key(&self.line_address(address))
+    }
+
+    /// Starts acquiring all lines covering `len` bytes at `address` with `permissions`. Lines
+    /// already held are skipped. Only waits for sources and credits, the Grants are processed by
+    /// `process_background`. All lines have arrived once `num_in_flight` drops to zero.
+    pub fn prefetch(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        len: usize,
+        permissions: OmnixtendPermissionChangeCap,
+    ) -> Result<()> {
+        if permissions == OmnixtendPermissionChangeCap::ToN {
+            Err(Error::InvalidPermissions {})?;
+        }
+        for (line, ..) in self.chunks(address, len) {
+            if !self.cache.contains_key(&line) {
+                self.make_room(operations, credits);
+            }
+            self.acquire_background(operations, credits, line, permissions, true)?;
+        }
+        Ok(())
+    }
+
+    /// Releases all lines and removes all pins.
+    pub fn release(&self, operations: &Operations, credits: &Credits) -> Result<()> {
+        self.pinned.lock().clear();
+        let lines: Vec<u64> = self.cache.iter().map(|v| *v.key()).collect();
+        for addr in lines {
+            self.release_line_logged(
+                addr,
+                Some(OmnixtendPermissionChangeCap::ToN),
+                operations,
+                credits,
+            );
+        }
         Ok(())
     }
 
@@ -84,29 +711,108 @@ impl Cache {
         credits: &Credits,
         addr: u64,
     ) -> Result<()> {
-        match self.cache.get_mut(&addr) {
-            Some(mut entry) => {
-                if entry.valid
-                    && !entry.release_pending
-                    && entry.permissions != OmnixtendPermissionChangeCap::ToN
-                {
-                    self.handle_release(addr, entry.value_mut(), operations, credits);
-                    Ok(())
-                } else {
-                    Err(Error::NotInCache { addr })
+        self.downgrade_addr(operations, credits, addr, OmnixtendPermissionChangeCap::ToN)
+    }
+
+    /// Writes the line holding `addr` back to the endpoint if it is dirty. The line stays in the
+    /// cache with Trunk permissions, i.e., a Release with TtoT is sent.
+    pub fn clean_addr(&self, operations: &Operations, credits: &Credits, addr: u64) -> Result<()> {
+        let addr = self.line_address(addr);
+        if self.release_line(addr, None, operations, credits)? {
+            Ok(())
+        } else {
+            Err(Error::NotInCache { addr })
+        }
+    }
+
+    /// Voluntarily lowers the permissions of the line holding `addr` to `to`. Dirty data is
+    /// written back, the line is only dropped from the cache for `ToN`. A pinned line stays
+    /// pinned with the lowered permissions, `ToN` removes the pin.
+    pub fn downgrade_addr(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        addr: u64,
+        to: OmnixtendPermissionChangeCap,
+    ) -> Result<()> {
+        let addr = self.line_address(addr);
+        let ret = self.release_line(addr, Some(to), operations, credits)?;
+        {
+            let mut pinned = self.pinned.lock();
+            if to == OmnixtendPermissionChangeCap::ToN {
+                pinned.remove(&addr);
+            } else if let Some(p) = pinned.get_mut(&addr) {
+                *p = to;
+            }
+        }
+        if ret {
+            Ok(())
+        } else {
+            Err(Error::NotInCache { addr })
+        }
+    }
+
+    /// Writes back all dirty lines while keeping them cached. Afterwards, every write performed
+    /// before the call is visible at the endpoint. Lines that are currently being released or
+    /// probed are written back by those transactions.
+    pub fn flush_all(&self, operations: &Operations, credits: &Credits) -> Result<()> {
+        let lines: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|v| v.modified)
+            .map(|v| *v.key())
+            .collect();
+        for addr in lines {
+            self.release_line(addr, None, operations, credits)?;
+        }
+        Ok(())
+    }
+
+    /// Releases least recently used lines until a new line fits into the cache.
+    fn make_room(&self, operations: &Operations, credits: &Credits) {
+        let capacity = match self.capacity {
+            Some(c) => c,
+            None => return,
+        };
+        while self.cache.len() >= capacity {
+            let pinned = self.pinned.lock().clone();
+            let victim = self
+                .cache
+                .iter()
+                .filter(|v| Self::releasable(v.value()) && !pinned.contains_key(v.key()))
+                .min_by_key(|v| v.last_access)
+                .map(|v| *v.key());
+            match victim {
+                Some(addr) => {
+                    trace!("Sim {}: CACHED_T Evicting 0x{:X}", self.id, addr);
+                    if self.release_line_logged(
+                        addr,
+                        Some(OmnixtendPermissionChangeCap::ToN),
+                        operations,
+                        credits,
+                    ) {
+                        Counters::inc(&self.counters.evictions);
+                    }
                 }
+                None => break,
             }
-            None => Err(Error::NotInCache { addr }),
         }
     }
 
+    fn releasable(v: &CachedEntry) -> bool {
+        v.valid
+            && v.state == LineState::Stable
+            && v.permissions != OmnixtendPermissionChangeCap::ToN
+    }
+
     fn insert_entry(&self, address: u64, data: Vec<u8>, permissions: OmnixtendPermissionChangeCap) {
         let entry = CachedEntry {
             modified: false,
-            release_pending: false,
+            state: LineState::Stable,
             data,
             permissions,
             valid: true,
+            last_access: self.next_access(),
         };
         trace!(
             "Sim {}: CACHED_T Adding entry for 0x{:X} -> {:?}",
@@ -117,49 +823,64 @@ impl Cache {
         self.cache.insert(address, entry);
     }
 
-    fn change_permission_probe(
+    /// Determines the ProbeAck for a probe to `permission_request` without changing the line.
+    fn probe_response(
         &self,
         addr: u64,
         permission_request: OmnixtendPermissionChangeCap,
-    ) -> (u8, Option<Vec<u8>>, bool) {
-        let mut permission_change = get_permission_change(
-            &OmnixtendPermissionChangeCap::ToN,
-            &OmnixtendPermissionChangeCap::ToN,
-        );
-        let mut blocking = false;
+    ) -> ProbeResponse {
+        let entry = self.cache.get(&addr);
+        let cur = entry
+            .as_ref()
+            .map_or(OmnixtendPermissionChangeCap::ToN, |v| v.permissions);
+        // Probes only ever lower permissions, e.g., a probe toT leaves a Branch line untouched
+        let target = if (cur as u8) < (permission_request as u8) {
+            permission_request
+        } else {
+            cur
+        };
+        let permission_change = get_permission_change(&cur, &target);
+        self.checker
+            .check_probe(addr, cur, permission_request, permission_change);
+
+        ProbeResponse {
+            permission_change,
+            permissions: target,
+            writeback: entry
+                .filter(|v| cur != target && v.modified)
+                .map(|v| v.data.clone()),
+        }
+    }
+
+    /// Lowers the permissions of the line at `addr` as announced by `response`. Lines without
+    /// permissions are dropped.
+    fn apply_probe(&self, addr: u64, response: &ProbeResponse) {
         if let Some(mut v) = self.cache.get_mut(&addr) {
-            if v.release_pending {
-                blocking = true;
+            if v.permissions == response.permissions {
+                trace!(
+                    "Sim {}: CACHED_T Permission of cache line 0x{:X} stays {:?}.",
+                    self.id,
+                    addr,
+                    v.permissions,
+                );
             } else {
-                permission_change = get_permission_change(&v.permissions, &permission_request);
-                if v.permissions == permission_request
-                    || v.permissions == OmnixtendPermissionChangeCap::ToN
-                {
-                    trace!(
-                        "Sim {}: CACHED_T Permission of cache line 0x{:X} stays {:?}.",
-                        self.id,
-                        addr,
-                        v.permissions,
-                    );
-                } else {
-                    trace!(
-                        "Sim {}: CACHED_T Changing permission of cache line 0x{:X} from {:?} to {:?} ({:?}) (Dirty {}).",
-                        self.id,
-                        addr,
-                        v.permissions,
-                        permission_request,
-                        permission_change,
-                        v.modified
-                    );
-                    v.permissions = permission_request;
-                    if v.modified {
-                        v.modified = false;
-                        return (permission_change, Some(v.data.clone()), false);
-                    }
+                trace!(
+                    "Sim {}: CACHED_T Changing permission of cache line 0x{:X} from {:?} to {:?} ({:?}) (Dirty {}).",
+                    self.id,
+                    addr,
+                    v.permissions,
+                    response.permissions,
+                    response.permission_change,
+                    v.modified
+                );
+                v.permissions = response.permissions;
+                v.valid = response.permissions != OmnixtendPermissionChangeCap::ToN;
+                if response.writeback.is_some() {
+                    v.modified = false;
                 }
             }
         }
-        (permission_change, None, blocking)
+        self.cache.remove_if(&addr, |_, v| !v.valid);
     }
 
     pub fn write(
@@ -169,50 +890,24 @@ impl Cache {
         address: u64,
         data: u64,
     ) -> Result<()> {
-        loop {
-            let mut perm_cur = OmnixtendPermissionChangeCap::ToN;
-            let mut release_pending = false;
-            if let Some(mut key) = self.cache.get_mut(&address) {
-                if key.valid
-                    && !key.release_pending
-                    && key.permissions == OmnixtendPermissionChangeCap::ToT
-                {
-                    key.modified = true;
-                    key.data = u64::to_ne_bytes(data).to_vec();
-                    trace!(
-                        "Sim {}: CACHED_T Writing {} to 0x{:X} with permissions {:?}.",
-                        self.id,
-                        data,
-                        address,
-                        key.permissions
-                    );
-                    break;
-                }
-                release_pending = key.release_pending;
-                perm_cur = key.permissions;
-            }
-
-            if !release_pending {
-                let entry = operations
-                    .perform(
-                        &TLOperations::AcquireBlock(PermOp {
-                            address,
-                            len: 8,
-                            permissions: get_permission_change_grow(
-                                &perm_cur,
-                                &OmnixtendPermissionChangeCap::ToT,
-                            ),
-                        }),
-                        credits,
-                    )
-                    .context(OperationsSnafu)?
-                    .get_data();
-                self.insert_entry(address, entry, OmnixtendPermissionChangeCap::ToT);
-            } else {
-                thread::yield_now();
-            }
-        }
-        Ok(())
+        let (line, offset) = self.locate(address)?;
+        self.access(
+            operations,
+            credits,
+            line,
+            OmnixtendPermissionChangeCap::ToT,
+            |entry| {
+                entry.modified = true;
+                entry.data[offset..offset + 8].copy_from_slice(&u64::to_ne_bytes(data));
+                trace!(
+                    "Sim {}: CACHED_T Writing {} to 0x{:X} with permissions {:?}.",
+                    self.id,
+                    data,
+                    address,
+                    entry.permissions
+                );
+            },
+        )
     }
 
     pub fn rmw(
@@ -222,106 +917,192 @@ impl Cache {
         address: u64,
         f: impl FnOnce(&mut u64),
     ) -> Result<u64> {
-        loop {
-            let mut perm_cur = OmnixtendPermissionChangeCap::ToN;
-            let mut release_pending = false;
-            if let Some(mut key) = self.cache.get_mut(&address) {
-                if key.valid
-                    && !key.release_pending
-                    && key.permissions == OmnixtendPermissionChangeCap::ToT
-                {
-                    key.modified = true;
-                    let mut val =
-                        u64::from_ne_bytes(key.data[..].try_into().expect("Not enough data."));
-                    let val_cpy = val;
-                    f(&mut val);
-                    trace!(
-                        "Sim {}: CACHED_T Changing 0x{:X} from {} to {} with permissions {:?}.",
-                        self.id,
-                        address,
-                        val_cpy,
-                        val,
-                        key.permissions
-                    );
-                    key.data = u64::to_ne_bytes(val).to_vec();
-                    return Ok(val);
-                }
-                release_pending = key.release_pending;
-                perm_cur = key.permissions;
-            }
-
-            if !release_pending {
-                let entry = operations
-                    .perform(
-                        &TLOperations::AcquireBlock(PermOp {
-                            address,
-                            len: 8,
-                            permissions: get_permission_change_grow(
-                                &perm_cur,
-                                &OmnixtendPermissionChangeCap::ToT,
-                            ),
-                        }),
-                        credits,
-                    )
-                    .context(OperationsSnafu)?
-                    .get_data();
-                self.insert_entry(address, entry, OmnixtendPermissionChangeCap::ToT);
-            } else {
-                thread::yield_now();
-            }
-        }
+        let (line, offset) = self.locate(address)?;
+        self.access(
+            operations,
+            credits,
+            line,
+            OmnixtendPermissionChangeCap::ToT,
+            |entry| {
+                entry.modified = true;
+                let mut val = u64::from_ne_bytes(
+                    entry.data[offset..offset + 8]
+                        .try_into()
+                        .expect("Not enough data."),
+                );
+                let val_cpy = val;
+                f(&mut val);
+                trace!(
+                    "Sim {}: CACHED_T Changing 0x{:X} from {} to {} with permissions {:?}.",
+                    self.id,
+                    address,
+                    val_cpy,
+                    val,
+                    entry.permissions
+                );
+                entry.data[offset..offset + 8].copy_from_slice(&u64::to_ne_bytes(val));
+                val
+            },
+        )
     }
 
     pub fn read(&self, operations: &Operations, credits: &Credits, address: u64) -> Result<u64> {
-        loop {
-            let mut perm_cur = OmnixtendPermissionChangeCap::ToN;
-            let mut release_pending = false;
-            if let Some(key) = self.cache.get(&address) {
-                if key.valid
-                    && !key.release_pending
-                    && (key.permissions == OmnixtendPermissionChangeCap::ToT
-                        || key.permissions == OmnixtendPermissionChangeCap::ToB)
-                {
-                    let val =
-                        u64::from_ne_bytes(key.data[..].try_into().expect("Not enough data."));
-                    trace!(
-                        "Sim {}: CACHED_T Reading 0x{:X} as 0x{:X} with permissions {:?}.",
-                        self.id,
-                        val,
-                        address,
-                        key.permissions
-                    );
-                    return Ok(val);
-                }
-                release_pending = key.release_pending;
-                perm_cur = key.permissions;
-            }
-
-            if !release_pending {
-                let perm_change =
-                    get_permission_change_grow(&perm_cur, &OmnixtendPermissionChangeCap::ToB);
+        let (line, offset) = self.locate(address)?;
+        self.access(
+            operations,
+            credits,
+            line,
+            OmnixtendPermissionChangeCap::ToB,
+            |entry| {
+                let val = u64::from_ne_bytes(
+                    entry.data[offset..offset + 8]
+                        .try_into()
+                        .expect("Not enough data."),
+                );
                 trace!(
-                    "Sim {}: CACHED_T Reading requesting change for 0x{:X} -> {:?} ",
+                    "Sim {}: CACHED_T Reading 0x{:X} as 0x{:X} with permissions {:?}.",
                     self.id,
+                    val,
                     address,
-                    perm_change
+                    entry.permissions
                 );
-                let entry = operations
-                    .perform(
-                        &TLOperations::AcquireBlock(PermOp {
-                            address,
-                            len: 8,
-                            permissions: perm_change,
-                        }),
-                        credits,
-                    )
-                    .context(OperationsSnafu)?
-                    .get_data();
-                self.insert_entry(address, entry, OmnixtendPermissionChangeCap::ToB);
-            } else {
-                thread::yield_now();
+                val
+            },
+        )
+    }
+
+    /// Splits the byte range starting at `address` into the parts covered by each line. Returns
+    /// tuples of line address, offset in the line, offset in the range and length of the part.
+    fn chunks(&self, address: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize, usize)> {
+        let line_size = self.line_size;
+        let mut pos = 0;
+        std::iter::from_fn(move || {
+            if pos >= len {
+                return None;
             }
+            let cur = address + pos as u64;
+            let line = cur & !(line_size as u64 - 1);
+            let offset = (cur - line) as usize;
+            let chunk = (line_size - offset).min(len - pos);
+            let ret = (line, offset, pos, chunk);
+            pos += chunk;
+            Some(ret)
+        })
+    }
+
+    /// Reads `buf.len()` bytes starting at `address` without any alignment restrictions. Lines
+    /// are acquired one after another, a read spanning multiple lines is not atomic.
+    pub fn read_bytes(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        for (line, offset, pos, len) in self.chunks(address, buf.len()) {
+            self.access(
+                operations,
+                credits,
+                line,
+                OmnixtendPermissionChangeCap::ToB,
+                |entry| buf[pos..pos + len].copy_from_slice(&entry.data[offset..offset + len]),
+            )?;
         }
+        trace!(
+            "Sim {}: CACHED_T Read {} bytes from 0x{:X}.",
+            self.id,
+            buf.len(),
+            address
+        );
+        Ok(())
+    }
+
+    /// Writes `data` starting at `address` without any alignment restrictions. Lines are acquired
+    /// one after another, a write spanning multiple lines is not atomic.
+    pub fn write_bytes(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        for (line, offset, pos, len) in self.chunks(address, data.len()) {
+            self.access(
+                operations,
+                credits,
+                line,
+                OmnixtendPermissionChangeCap::ToT,
+                |entry| {
+                    entry.modified = true;
+                    entry.data[offset..offset + len].copy_from_slice(&data[pos..pos + len]);
+                },
+            )?;
+        }
+        trace!(
+            "Sim {}: CACHED_T Wrote {} bytes to 0x{:X}.",
+            self.id,
+            data.len(),
+            address
+        );
+        Ok(())
+    }
+
+    pub fn read_u8(&self, operations: &Operations, credits: &Credits, address: u64) -> Result<u8> {
+        let mut buf = [0; 1];
+        self.read_bytes(operations, credits, address, &mut buf)?;
+        Ok(u8::from_ne_bytes(buf))
+    }
+
+    pub fn write_u8(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        data: u8,
+    ) -> Result<()> {
+        self.write_bytes(operations, credits, address, &data.to_ne_bytes())
+    }
+
+    pub fn read_u16(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+    ) -> Result<u16> {
+        let mut buf = [0; 2];
+        self.read_bytes(operations, credits, address, &mut buf)?;
+        Ok(u16::from_ne_bytes(buf))
+    }
+
+    pub fn write_u16(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        data: u16,
+    ) -> Result<()> {
+        self.write_bytes(operations, credits, address, &data.to_ne_bytes())
+    }
+
+    pub fn read_u32(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+    ) -> Result<u32> {
+        let mut buf = [0; 4];
+        self.read_bytes(operations, credits, address, &mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+
+    pub fn write_u32(
+        &self,
+        operations: &Operations,
+        credits: &Credits,
+        address: u64,
+        data: u32,
+    ) -> Result<()> {
+        self.write_bytes(operations, credits, address, &data.to_ne_bytes())
     }
 
     pub fn retrieve_overview(&self) -> Vec<CacheStatus> {
@@ -340,46 +1121,79 @@ impl Cache {
     }
 
     pub fn add_probe(&self, probe: Probe) {
+        Counters::inc(match OmnixtendPermissionChangeCap::from(probe.0.param) {
+            OmnixtendPermissionChangeCap::ToT => &self.counters.probes_to_t,
+            OmnixtendPermissionChangeCap::ToB => &self.counters.probes_to_b,
+            OmnixtendPermissionChangeCap::ToN => &self.counters.probes_to_n,
+        });
         self.probes.lock().push(probe)
     }
 
+    /// Answers the pending probes in order. Probes for lines that are being released are kept until
+    /// the ReleaseAck has arrived. Probes for lines that are being acquired are answered right away,
+    /// the manager might wait for the ProbeAck before sending the Grant. Other lines are only
+    /// downgraded once their ProbeAck has been sent, a ProbeAck that could not be sent is retried
+    /// with the same permissions and data.
     pub fn process_probes(&self, operations: &Operations, credits: &Credits) {
+        let mut stalled = false;
         self.probes.lock().retain(|(msg, addr)| {
-            let permission_request = OmnixtendPermissionChangeCap::from(msg.param);
-
-            let (perm_change, writeback, blocked) =
-                self.change_permission_probe(*addr, permission_request);
-
-            if blocked {
+            // Later probes might be for the same line, they have to wait for the earlier ones
+            if stalled {
                 return true;
             }
+            let permission_request = OmnixtendPermissionChangeCap::from(msg.param);
+            let line = self.line_address(*addr);
+
+            let (response, mshr) = {
+                let mut mshrs = self.mshrs.lock();
+                let in_flight = mshrs.get(&line).cloned();
+                match in_flight {
+                    Some(m) if m.state == LineState::Releasing => return true,
+                    Some(m) if m.state == LineState::Probing => {
+                        let response = m.probe.clone().expect("Probing MSHR has a ProbeAck.");
+                        (response, Some(m))
+                    }
+                    // Lines being acquired are clean, they are downgraded right away as the Grant
+                    // might arrive as soon as the ProbeAck has been sent
+                    Some(_) => {
+                        let response = self.probe_response(line, permission_request);
+                        self.apply_probe(line, &response);
+                        (response, None)
+                    }
+                    // Accesses have to wait for the ProbeAck before acquiring the line again
+                    None => {
+                        let response = self.probe_response(line, permission_request);
+                        if let Some(mut entry) = self.cache.get_mut(&line) {
+                            entry.state = LineState::Probing;
+                        }
+                        let mshr = Mshr::probing(response.clone());
+                        mshrs.insert(line, mshr.clone());
+                        (response, Some(mshr))
+                    }
+                }
+            };
 
-            if let (Some(data), 6) = (writeback, msg.opcode) {
+            let with_data = response.writeback.is_some() && msg.opcode == 6;
+            let ret = if let (Some(data), 6) = (&response.writeback, msg.opcode) {
                 trace!(
                     "Sim {}: CACHED_T Sending ProbeAckData of {} bytes to 0x{:X}: {:?}",
                     self.id,
                     data.len(),
                     addr,
-                    &data
+                    data
                 );
 
-                if let Err(e) = operations
-                    .perform(
-                        &TLOperations::ProbeAckData(ProbeDataOp {
-                            probe: ProbeOp {
-                                address: *addr,
-                                size: msg.size,
-                                permission_change: perm_change,
-                            },
-                            data: &data,
-                        }),
-                        credits,
-                    )
-                    .context(OperationsSnafu)
-                {
-                    error!("Failed to process probe for {:x}. Retrying: {:?}", addr, e);
-                    return true;
-                }
+                operations.perform(
+                    &TLOperations::ProbeAckData(ProbeDataOp {
+                        probe: ProbeOp {
+                            address: *addr,
+                            size: msg.size,
+                            permission_change: response.permission_change,
+                        },
+                        data,
+                    }),
+                    credits,
+                )
             } else {
                 trace!(
                     "Sim {}: CACHED_T Sending ProbeAck to 0x{:X}.",
@@ -387,75 +1201,149 @@ impl Cache {
                     addr
                 );
 
-                if let Err(e) = operations
-                    .perform(
-                        &TLOperations::ProbeAck(ProbeOp {
-                            address: *addr,
-                            size: msg.size,
-                            permission_change: perm_change,
-                        }),
-                        credits,
-                    )
-                    .context(OperationsSnafu)
-                {
+                operations.perform(
+                    &TLOperations::ProbeAck(ProbeOp {
+                        address: *addr,
+                        size: msg.size,
+                        permission_change: response.permission_change,
+                    }),
+                    credits,
+                )
+            };
+
+            // Running out of credits is the only error a retry can recover from
+            let sent = match ret {
+                Ok(_) => true,
+                Err(e @ crate::operations::Error::Timeout {}) => {
                     error!("Failed to process probe for {:x}. Retrying: {:?}", addr, e);
+                    stalled = true;
                     return true;
                 }
+                Err(e) => {
+                    error!("Failed to process probe for {:x}. Dropping: {:?}", addr, e);
+                    false
+                }
+            };
+
+            if let Some(mshr) = mshr {
+                {
+                    let mut mshrs = self.mshrs.lock();
+                    if let Some(mut entry) = self.cache.get_mut(&line) {
+                        entry.state = LineState::Stable;
+                    }
+                    if sent {
+                        self.apply_probe(line, &response);
+                    }
+                    mshrs.remove(&line);
+                }
+                mshr.finish();
+            }
+
+            if sent && with_data {
+                Counters::inc(&self.counters.probe_writebacks);
             }
             false
         });
     }
 
-    fn handle_release(
+    fn is_downgrade(from: OmnixtendPermissionChangeCap, to: OmnixtendPermissionChangeCap) -> bool {
+        from as u8 <= to as u8
+    }
+
+    /// Releases the line at `addr` to `to`, `None` keeps the current permissions and only writes
+    /// back dirty data. The line is removed from the cache for `ToN`. Accesses to the line wait for
+    /// the ReleaseAck while probes and accesses to other lines continue. Returns false if the line
+    /// is not held or has a transaction in flight.
+    fn release_line(
         &self,
         addr: u64,
-        v: &mut CachedEntry,
+        to: Option<OmnixtendPermissionChangeCap>,
         operations: &Operations,
         credits: &Credits,
-    ) {
-        v.release_pending = true;
-        trace!("Sim {}: CACHED_T Prepare release of 0x{:X}", self.id, addr);
-        trace!("Sim {}: CACHED_T Releasing 0x{:X}", self.id, addr);
-        if v.modified {
-            operations
-                .perform(
-                    &TLOperations::ReleaseData(ReleaseDataOp {
-                        release: ReleaseOp {
-                            address: addr,
-                            len: v.data.len(),
-                            perm_from: v.permissions,
-                            perm_to: OmnixtendPermissionChangeCap::ToN,
-                        },
-                        data: &v.data[..],
-                    }),
-                    credits,
-                )
-                .context(OperationsSnafu)
-                .unwrap_or_else(|err| {
-                    trace!("Sim {}: CACHED_T Release failed {:?}", self.id, err);
-                    TLResult::None
-                });
-        } else {
-            operations
-                .perform(
-                    &TLOperations::Release(ReleaseOp {
-                        address: addr,
-                        len: v.data.len(),
-                        perm_from: v.permissions,
-                        perm_to: OmnixtendPermissionChangeCap::ToN,
-                    }),
-                    credits,
-                )
-                .context(OperationsSnafu)
-                .unwrap_or_else(|err| {
-                    trace!("Sim {}: CACHED_T Release failed {:?}", self.id, err);
-                    TLResult::None
-                });
+    ) -> Result<bool> {
+        let (mshr, perm_from, to, writeback) = {
+            let mut mshrs = self.mshrs.lock();
+            if mshrs.contains_key(&addr) {
+                return Ok(false);
+            }
+            let (perm_from, to, writeback) = match self.cache.get_mut(&addr) {
+                Some(mut v) if Self::releasable(&v) => {
+                    let to = to.unwrap_or(v.permissions);
+                    if v.permissions == to && !v.modified {
+                        return Ok(true);
+                    }
+                    if !Self::is_downgrade(v.permissions, to) {
+                        Err(Error::InvalidDowngrade {
+                            addr,
+                            from: v.permissions,
+                            to,
+                        })?;
+                    }
+                    v.state = LineState::Releasing;
+                    (v.permissions, to, v.modified.then(|| v.data.clone()))
+                }
+                _ => return Ok(false),
+            };
+            let mshr = Mshr::new(LineState::Releasing);
+            mshrs.insert(addr, mshr.clone());
+            (mshr, perm_from, to, writeback)
+        };
+
+        trace!(
+            "Sim {}: CACHED_T Releasing 0x{:X} from {:?} to {:?}",
+            self.id,
+            addr,
+            perm_from,
+            to
+        );
+        self.checker
+            .check_release(addr, perm_from, to, get_permission_change(&perm_from, &to));
+        let release = ReleaseOp {
+            address: addr,
+            len: self.line_size,
+            perm_from,
+            perm_to: to,
+        };
+        let op = match &writeback {
+            Some(data) => TLOperations::ReleaseData(ReleaseDataOp { release, data }),
+            None => TLOperations::Release(release),
+        };
+        Counters::inc(&self.counters.releases);
+        if writeback.is_some() {
+            Counters::inc(&self.counters.release_writebacks);
         }
+        let ret = operations.perform(&op, credits).context(OperationsSnafu);
         trace!("Sim {}: CACHED_T Released 0x{:X}", self.id, addr);
-        v.modified = false;
-        v.permissions = OmnixtendPermissionChangeCap::ToN;
-        v.valid = false;
-        v.release_pending = false;
+
+        {
+            let mut mshrs = self.mshrs.lock();
+            if to == OmnixtendPermissionChangeCap::ToN {
+                self.cache.remove(&addr);
+            } else if let Some(mut v) = self.cache.get_mut(&addr) {
+                if ret.is_ok() {
+                    v.permissions = to;
+                    v.modified = false;
+                }
+                v.state = LineState::Stable;
+            }
+            mshrs.remove(&addr);
+        }
+        mshr.finish();
+        ret.map(|_| true)
+    }
+
+    /// Releases the line without reporting errors, used where there is nobody to report to.
+    fn release_line_logged(
+        &self,
+        addr: u64,
+        to: Option<OmnixtendPermissionChangeCap>,
+        operations: &Operations,
+        credits: &Credits,
+    ) -> bool {
+        self.release_line(addr, to, operations, credits)
+            .unwrap_or_else(|err| {
+                trace!("Sim {}: CACHED_T Release failed {:?}", self.id, err);
+                false
+            })
     }
 }
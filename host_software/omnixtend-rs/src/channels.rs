@@ -10,205 +10,423 @@ use std::cmp::max;
 
 use crate::{
     cache::Probe,
-    tilelink_messages::{ChanABCDTilelinkMessage, OmnixtendChannel},
+    responder::Request,
+    tilelink_messages::{ChanABCDTilelinkMessage, ChanETilelinkMessage, OmnixtendChannel},
 };
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Payload too short: {}B", pl))]
     ShortPayload { pl: usize },
+
+    #[snafu(display("Message at {} truncated: {}B of payload left", pos, remaining))]
+    TruncatedHeader { pos: usize, remaining: usize },
+
+    #[snafu(display("Invalid channel {} in message at {}", chan, pos))]
+    BadChannel { chan: u64, pos: usize },
+
+    #[snafu(display(
+        "Message at {} carries {}B of data but only {}B of payload are left",
+        pos,
+        size,
+        remaining
+    ))]
+    SizeExceedsPayload {
+        pos: usize,
+        size: usize,
+        remaining: usize,
+    },
+
+    #[snafu(display(
+        "Unknown opcode {} on channel {:?} in message at {}",
+        opcode,
+        chan,
+        pos
+    ))]
+    UnknownOpcode {
+        chan: OmnixtendChannel,
+        opcode: u8,
+        pos: usize,
+    },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+pub type Credit = (OmnixtendChannel, usize);
+
+/// Response received on channel D. The `err` field of the message is split into `denied` and
+/// `corrupt`. Data is kept for corrupt responses as well.
+#[derive(Debug)]
+pub struct Response {
+    pub source: u32,
+    pub sink: u32,
+    pub opcode: u8,
+    pub denied: bool,
+    pub corrupt: bool,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct ParsedMessages {
+    credits: Vec<Credit>,
+    probes: Vec<Probe>,
+    responses: Vec<Response>,
+    requests: Vec<Request>,
+}
+
+type Handled<T> = Result<(Option<T>, Option<Credit>)>;
+
+/// Parses the TileLink messages of a packet payload. Malformed payloads result in an error instead
+/// of a panic, as they are received from the network. The handlers expect `pos` to point at the
+/// header of the message and move it behind the last flit of the message.
 pub struct Channel {}
 
 impl Channel {
     fn handle_chan_a(
         msg: &ChanABCDTilelinkMessage,
-        _payload: &[u8],
-        _pos: &mut usize,
-    ) -> Option<(OmnixtendChannel, usize)> {
-        panic!("Received channel A message as requester: {:?}", msg)
+        payload: &[u8],
+        pos: &mut usize,
+    ) -> Handled<Request> {
+        let start = *pos;
+        match msg.opcode {
+            0 | 2 | 3 => {
+                // PutFullData, ArithmeticData and LogicalData
+                *pos += 8; // Skip header
+                let address = Self::read_flit(payload, pos, start)?;
+                let (read_flits, data) = Self::handle_read(msg, payload, pos, start)?;
+                Ok((
+                    Some(Request::Message {
+                        msg: msg.clone(),
+                        address,
+                        data,
+                        mask: None,
+                    }),
+                    Some((msg.chan, 2 + read_flits)),
+                ))
+            }
+            1 => {
+                // PutPartialData: One mask flit precedes every 8 data flits. The mask is MSB
+                // first, i.e., the highest bit belongs to the first byte.
+                *pos += 8; // Skip header
+                let address = Self::read_flit(payload, pos, start)?;
+                let data_flits: usize = max((1 << msg.size) / 8, 1);
+                let mask_flits = data_flits.div_ceil(8);
+                Self::check_remaining(payload, *pos, (data_flits + mask_flits) * 8, start)?;
+                let mut data = Vec::with_capacity(data_flits * 8);
+                let mut mask = Vec::with_capacity(data_flits * 8);
+                let mut cur_mask = 0;
+                for i in 0..data_flits {
+                    if i % 8 == 0 {
+                        cur_mask = u64::from_ne_bytes(Self::flit_bytes(payload, *pos));
+                        *pos += 8;
+                    }
+                    data.extend_from_slice(&payload[*pos..*pos + 8]);
+                    *pos += 8;
+                    for b in 0..8 {
+                        mask.push((cur_mask >> (63 - ((i % 8) * 8 + b))) & 1 == 1);
+                    }
+                }
+                data.truncate(1 << msg.size);
+                mask.truncate(1 << msg.size);
+                Ok((
+                    Some(Request::Message {
+                        msg: msg.clone(),
+                        address,
+                        data,
+                        mask: Some(mask),
+                    }),
+                    Some((msg.chan, 2 + mask_flits + data_flits)),
+                ))
+            }
+            4..=7 => {
+                // Get, Intent, AcquireBlock and AcquirePerm
+                *pos += 8; // Skip header
+                let address = Self::read_flit(payload, pos, start)?;
+                Ok((
+                    Some(Request::Message {
+                        msg: msg.clone(),
+                        address,
+                        data: Vec::new(),
+                        mask: None,
+                    }),
+                    Some((msg.chan, 2)),
+                ))
+            }
+            _default => Err(Self::unknown_opcode(msg, start)),
+        }
     }
 
     fn handle_chan_b(
         msg: &ChanABCDTilelinkMessage,
         payload: &[u8],
         pos: &mut usize,
-    ) -> (Option<Probe>, Option<(OmnixtendChannel, usize)>) {
+    ) -> Handled<Probe> {
+        let start = *pos;
         match msg.opcode {
             6 | 7 => {
-                *pos += 8;
-                let addr = u64::from_be_bytes(
-                    payload[*pos..*pos + 8]
-                        .try_into()
-                        .expect("Vector too short"),
-                );
-                *pos += 8;
-                (Some((msg.clone(), addr)), Some((msg.chan, 2)))
+                // ProbeBlock and ProbePerm
+                *pos += 8; // Skip header
+                let addr = Self::read_flit(payload, pos, start)?;
+                Ok((Some((msg.clone(), addr)), Some((msg.chan, 2))))
             }
-            _default => (None, None),
+            _default => Err(Self::unknown_opcode(msg, start)),
         }
     }
 
     fn handle_chan_c(
         msg: &ChanABCDTilelinkMessage,
-        _payload: &[u8],
-        _pos: &mut usize,
-    ) -> Option<(OmnixtendChannel, usize)> {
-        panic!("Received channel C message as requester: {:?}", msg)
-    }
-
-    fn handle_read(msg: &ChanABCDTilelinkMessage, payload: &[u8], pos: &usize) -> (usize, Vec<u8>) {
-        let read_bytes = 1 << msg.size;
-        let read_flits = max(read_bytes / 8, 1);
-        let mut v = vec![0; read_bytes];
-        v.copy_from_slice(&payload[*pos..*pos + read_bytes]);
-        (read_flits, v)
+        payload: &[u8],
+        pos: &mut usize,
+    ) -> Handled<Request> {
+        let start = *pos;
+        match msg.opcode {
+            4 | 6 => {
+                // ProbeAck and Release
+                *pos += 8; // Skip header
+                let address = Self::read_flit(payload, pos, start)?;
+                Ok((
+                    Some(Request::Message {
+                        msg: msg.clone(),
+                        address,
+                        data: Vec::new(),
+                        mask: None,
+                    }),
+                    Some((msg.chan, 2)),
+                ))
+            }
+            5 | 7 => {
+                // ProbeAckData and ReleaseData
+                *pos += 8; // Skip header
+                let address = Self::read_flit(payload, pos, start)?;
+                let (read_flits, data) = Self::handle_read(msg, payload, pos, start)?;
+                Ok((
+                    Some(Request::Message {
+                        msg: msg.clone(),
+                        address,
+                        data,
+                        mask: None,
+                    }),
+                    Some((msg.chan, 2 + read_flits)),
+                ))
+            }
+            _default => Err(Self::unknown_opcode(msg, start)),
+        }
     }
 
     fn handle_chan_d(
         msg: &ChanABCDTilelinkMessage,
         payload: &[u8],
         pos: &mut usize,
-    ) -> (
-        Option<(u32, u32, crate::operations::Result<Vec<u8>>)>,
-        Option<(OmnixtendChannel, usize)>,
-    ) {
-        let denied: bool = (msg.err >> 1) & 1 == 1;
+    ) -> Handled<Response> {
+        let start = *pos;
+        let mut response = Response {
+            source: msg.source,
+            sink: 0,
+            opcode: msg.opcode,
+            denied: (msg.err >> 1) & 1 == 1,
+            corrupt: msg.err & 1 == 1,
+            data: Vec::new(),
+        };
         match msg.opcode {
-            0 => {
-                // AccessAck
+            0 | 2 | 6 => {
+                // AccessAck, HintAck and ReleaseAck
                 *pos += 8;
-
-                let v = if denied {
-                    Err(crate::operations::Error::UnalignedAccess {})
-                } else {
-                    Ok(Vec::new())
-                };
-                (Some((msg.source, 0, v)), Some((msg.chan, 1)))
+                Ok((Some(response), Some((msg.chan, 1))))
             }
             1 => {
-                // AccessAckData
+                // AccessAckData, answers Get as well as the atomics with the value before the
+                // operation
                 *pos += 8; // Skip header
-                let (read_flits, v) = Self::handle_read(msg, payload, pos);
-                *pos += read_flits * 8; // Skip data
-
-                let v = if denied {
-                    Err(crate::operations::Error::UnalignedAccess {})
-                } else {
-                    Ok(v)
-                };
+                let (read_flits, v) = Self::handle_read(msg, payload, pos, start)?;
+                response.data = v;
 
-                (Some((msg.source, 0, v)), Some((msg.chan, 1 + read_flits)))
+                Ok((Some(response), Some((msg.chan, 1 + read_flits))))
             }
             4 => {
                 // Grant
-                let v = if denied {
-                    Err(crate::operations::Error::UnalignedAccess {})
-                } else {
-                    Ok(Vec::new())
-                };
-                *pos += 8;
-                let sink = u64::from_be_bytes(
-                    payload[*pos..*pos + 8]
-                        .try_into()
-                        .expect("Vector too short"),
-                );
                 *pos += 8;
+                let sink = Self::read_flit(payload, pos, start)?;
+                response.sink = (sink & ((1 << 26) - 1)) as u32;
 
-                (
-                    Some((msg.source, (sink & ((1 << 26) - 1)) as u32, v)),
-                    Some((msg.chan, 2)),
-                )
+                Ok((Some(response), Some((msg.chan, 2))))
             }
             5 => {
                 // GrantData
                 *pos += 8; // Skip header
-                let sink = u64::from_be_bytes(
-                    payload[*pos..*pos + 8]
-                        .try_into()
-                        .expect("Vector too short"),
-                );
-                *pos += 8; // Skip sink
-                let (read_flits, v) = Self::handle_read(msg, payload, pos);
-                *pos += read_flits * 8; // Skip data
-
-                let v = if denied {
-                    Err(crate::operations::Error::UnalignedAccess {})
-                } else {
-                    Ok(v)
-                };
-
-                (
-                    Some((msg.source, (sink & ((1 << 26) - 1)) as u32, v)),
-                    Some((msg.chan, 2 + read_flits)),
-                )
-            }
-            6 => {
-                //ReleaseAck
-                *pos += 8;
-                (Some((msg.source, 0, Ok(Vec::new()))), Some((msg.chan, 1)))
+                let sink = Self::read_flit(payload, pos, start)?;
+                response.sink = (sink & ((1 << 26) - 1)) as u32;
+                let (read_flits, v) = Self::handle_read(msg, payload, pos, start)?;
+                response.data = v;
+
+                Ok((Some(response), Some((msg.chan, 2 + read_flits))))
             }
-            _default => panic!("Unhandled opcode on channel D: {}", msg.opcode),
+            _default => Err(Self::unknown_opcode(msg, start)),
         }
     }
 
-    fn handle_chan_e(
-        msg: &ChanABCDTilelinkMessage,
-        _payload: &[u8],
-        _pos: &mut usize,
-    ) -> Option<(OmnixtendChannel, usize)> {
-        panic!("Received channel E message as requester: {:?}", msg);
+    fn handle_chan_e(payload: &[u8], pos: &mut usize) -> Handled<Request> {
+        // GrantAck
+        let start = *pos;
+        let msg = ChanETilelinkMessage::from(Self::read_flit(payload, pos, start)?);
+        Ok((
+            Some(Request::GrantAck { sink: msg.sink }),
+            Some((msg.chan, 1)),
+        ))
     }
 
-    pub fn process_messages(
+    fn unknown_opcode(msg: &ChanABCDTilelinkMessage, pos: usize) -> Error {
+        Error::UnknownOpcode {
+            chan: msg.chan,
+            opcode: msg.opcode,
+            pos,
+        }
+    }
+
+    fn flit_bytes(payload: &[u8], pos: usize) -> [u8; 8] {
+        payload[pos..pos + 8]
+            .try_into()
+            .expect("Bounds are checked before.")
+    }
+
+    fn read_flit(payload: &[u8], pos: &mut usize, start: usize) -> Result<u64> {
+        if payload.len() < *pos + 8 {
+            Err(Error::TruncatedHeader {
+                pos: start,
+                remaining: payload.len() - start,
+            })?;
+        }
+        let v = u64::from_be_bytes(Self::flit_bytes(payload, *pos));
+        *pos += 8;
+        Ok(v)
+    }
+
+    fn check_remaining(payload: &[u8], pos: usize, size: usize, start: usize) -> Result<()> {
+        let remaining = payload.len().saturating_sub(pos);
+        if remaining < size {
+            Err(Error::SizeExceedsPayload {
+                pos: start,
+                size,
+                remaining,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reads the data of a message, which occupies at least one flit, and moves `pos` behind it.
+    fn handle_read(
+        msg: &ChanABCDTilelinkMessage,
         payload: &[u8],
-    ) -> Result<(
-        Vec<(OmnixtendChannel, usize)>,
-        Vec<Probe>,
-        Vec<(u32, u32, crate::operations::Result<Vec<u8>>)>,
-    )> {
+        pos: &mut usize,
+        start: usize,
+    ) -> Result<(usize, Vec<u8>)> {
+        let read_bytes = 1 << msg.size;
+        let read_flits = max(read_bytes / 8, 1);
+        Self::check_remaining(payload, *pos, read_flits * 8, start)?;
+        let v = payload[*pos..*pos + read_bytes].to_vec();
+        *pos += read_flits * 8;
+        Ok((read_flits, v))
+    }
+
+    /// Adds the messages in `payload` to `parsed` up to the first malformed one.
+    fn parse_messages(payload: &[u8], parsed: &mut ParsedMessages) -> Result<()> {
         if payload.len() < 8 {
             Err(Error::ShortPayload { pl: payload.len() })?;
         }
         trace!("Got payload of {} bytes.", payload.len());
 
-        let mut credits = Vec::new();
-        let mut probes = Vec::new();
-        let mut responses = Vec::new();
+        // The last flit holds the mask of message starts and is not parsed
+        let payload = &payload[..payload.len() - 8];
+
         let mut pos = 0;
-        while pos < payload.len() - 8 {
-            let msg = ChanABCDTilelinkMessage::from(u64::from_be_bytes(
-                payload[pos..pos + 8].try_into().expect("Vector too short"),
-            ));
+        while pos < payload.len() {
+            let header = u64::from_be_bytes(match payload.get(pos..pos + 8) {
+                Some(h) => h.try_into().expect("Slice has flit size."),
+                None => Err(Error::TruncatedHeader {
+                    pos,
+                    remaining: payload.len() - pos,
+                })?,
+            });
+            let chan = (header >> 60) & 0b111;
+            if chan > OmnixtendChannel::E as u64 {
+                Err(Error::BadChannel { chan, pos })?;
+            }
+            let msg = ChanABCDTilelinkMessage::from(header);
             if let Some(c) = match msg.chan {
-                OmnixtendChannel::A => Self::handle_chan_a(&msg, payload, &mut pos),
+                OmnixtendChannel::A => {
+                    let (request, credits) = Self::handle_chan_a(&msg, payload, &mut pos)?;
+                    if let Some(r) = request {
+                        parsed.requests.push(r);
+                    }
+                    credits
+                }
                 OmnixtendChannel::B => {
-                    let (probes_in, credits) = Self::handle_chan_b(&msg, payload, &mut pos);
+                    let (probes_in, credits) = Self::handle_chan_b(&msg, payload, &mut pos)?;
                     if let Some(p) = probes_in {
-                        probes.push(p);
+                        parsed.probes.push(p);
+                    }
+                    credits
+                }
+                OmnixtendChannel::C => {
+                    let (request, credits) = Self::handle_chan_c(&msg, payload, &mut pos)?;
+                    if let Some(r) = request {
+                        parsed.requests.push(r);
                     }
                     credits
                 }
-                OmnixtendChannel::C => Self::handle_chan_c(&msg, payload, &mut pos),
                 OmnixtendChannel::D => {
-                    let (response, credits) = Self::handle_chan_d(&msg, payload, &mut pos);
+                    let (response, credits) = Self::handle_chan_d(&msg, payload, &mut pos)?;
                     if let Some(r) = response {
-                        responses.push(r);
+                        parsed.responses.push(r);
+                    }
+                    credits
+                }
+                OmnixtendChannel::E => {
+                    let (request, credits) = Self::handle_chan_e(payload, &mut pos)?;
+                    if let Some(r) = request {
+                        parsed.requests.push(r);
                     }
                     credits
                 }
-                OmnixtendChannel::E => Self::handle_chan_e(&msg, payload, &mut pos),
                 OmnixtendChannel::INVALID => {
+                    // Padding
                     pos += 8;
                     None
                 }
             } {
-                credits.push(c);
+                parsed.credits.push(c);
             }
         }
-        Ok((credits, probes, responses))
+        Ok(())
+    }
+
+    /// Parses the messages of a packet received in the requester role. The messages in front of a
+    /// malformed one are returned together with the error.
+    pub fn process_messages(
+        payload: &[u8],
+    ) -> (Vec<Credit>, Vec<Probe>, Vec<Response>, Result<()>) {
+        let mut parsed = ParsedMessages::default();
+        let res = Self::parse_messages(payload, &mut parsed);
+        if !parsed.requests.is_empty() {
+            error!(
+                "Received {} channel A/C/E messages as requester. Dropping: {:?}",
+                parsed.requests.len(),
+                parsed.requests
+            );
+        }
+        (parsed.credits, parsed.probes, parsed.responses, res)
+    }
+
+    /// Parses the messages of a packet received in the responder role. The messages in front of a
+    /// malformed one are returned together with the error.
+    pub fn process_requests(payload: &[u8]) -> (Vec<Credit>, Vec<Request>, Result<()>) {
+        let mut parsed = ParsedMessages::default();
+        let res = Self::parse_messages(payload, &mut parsed);
+        if !parsed.probes.is_empty() || !parsed.responses.is_empty() {
+            error!(
+                "Received {} channel B/D messages as responder. Dropping.",
+                parsed.probes.len() + parsed.responses.len()
+            );
+        }
+        (parsed.credits, parsed.requests, res)
     }
 }
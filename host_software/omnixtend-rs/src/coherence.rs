@@ -0,0 +1,209 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use parking_lot::Mutex;
+
+use crate::tilelink_messages::{
+    OmnixtendPermissionChangeCap, OmnixtendPermissionChangeGrow, OmnixtendPermissionChangePrune,
+    OmnixtendPermissionChangeReport,
+};
+
+/// Permission transition of a client that is not allowed by the TileLink 1.8 TL-C state machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub address: u64,
+    pub old: OmnixtendPermissionChangeCap,
+    pub requested: OmnixtendPermissionChangeCap,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:X} {:?} -> {:?}: {}",
+            self.address, self.old, self.requested, self.message
+        )
+    }
+}
+
+/// Validates the permission changes of a client cache against the TL-C state machine. The
+/// expected params are derived independently of the helpers in `tilelink_messages` used to build
+/// the messages. Checks are skipped unless enabled, violations are logged and recorded.
+#[derive(Default)]
+pub struct CoherenceChecker {
+    enabled: AtomicBool,
+    violations: Mutex<Vec<Violation>>,
+}
+
+impl CoherenceChecker {
+    pub fn new(enabled: bool) -> Self {
+        CoherenceChecker {
+            enabled: AtomicBool::new(enabled),
+            violations: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Violations recorded so far.
+    pub fn violations(&self) -> Vec<Violation> {
+        self.violations.lock().clone()
+    }
+
+    /// Returns the recorded violations and clears them.
+    pub fn take_violations(&self) -> Vec<Violation> {
+        std::mem::take(&mut *self.violations.lock())
+    }
+
+    /// Checks an Acquire from `cur` to `requested` using `grow`.
+    pub fn check_acquire(
+        &self,
+        address: u64,
+        cur: OmnixtendPermissionChangeCap,
+        requested: OmnixtendPermissionChangeCap,
+        grow: OmnixtendPermissionChangeGrow,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        match grow_param(cur, requested) {
+            None => self.record(
+                address,
+                cur,
+                requested,
+                format!("Acquire with {:?} does not grow permissions.", grow),
+            ),
+            Some(expected) if expected != grow => self.record(
+                address,
+                cur,
+                requested,
+                format!("Acquire with {:?} instead of {:?}.", grow, expected),
+            ),
+            _ => {}
+        }
+    }
+
+    /// Checks the ProbeAck `param` of a line holding `cur` for a probe with cap `cap`.
+    pub fn check_probe(
+        &self,
+        address: u64,
+        cur: OmnixtendPermissionChangeCap,
+        cap: OmnixtendPermissionChangeCap,
+        param: u8,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        // A probe never grows permissions, the line ends up with the lower of both
+        let target = if (cur as u8) < (cap as u8) { cap } else { cur };
+        match shrink_param(cur, target) {
+            Some(expected) if expected == param => {}
+            expected => self.record(
+                address,
+                cur,
+                cap,
+                format!("ProbeAck with param {} instead of {:?}.", param, expected),
+            ),
+        }
+    }
+
+    /// Checks a Release from `from` to `to` with the prune or report `param`.
+    pub fn check_release(
+        &self,
+        address: u64,
+        from: OmnixtendPermissionChangeCap,
+        to: OmnixtendPermissionChangeCap,
+        param: u8,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        if from == OmnixtendPermissionChangeCap::ToN {
+            self.record(
+                address,
+                from,
+                to,
+                "Release of a line without permissions.".to_string(),
+            );
+            return;
+        }
+        match shrink_param(from, to) {
+            None => self.record(
+                address,
+                from,
+                to,
+                format!("Release with param {} grows permissions.", param),
+            ),
+            Some(expected) if expected != param => self.record(
+                address,
+                from,
+                to,
+                format!("Release with param {} instead of {}.", param, expected),
+            ),
+            _ => {}
+        }
+    }
+
+    fn record(
+        &self,
+        address: u64,
+        old: OmnixtendPermissionChangeCap,
+        requested: OmnixtendPermissionChangeCap,
+        message: String,
+    ) {
+        let violation = Violation {
+            address,
+            old,
+            requested,
+            message,
+        };
+        error!("Coherence violation at {}", violation);
+        self.violations.lock().push(violation);
+    }
+}
+
+fn grow_param(
+    from: OmnixtendPermissionChangeCap,
+    to: OmnixtendPermissionChangeCap,
+) -> Option<OmnixtendPermissionChangeGrow> {
+    use OmnixtendPermissionChangeCap::*;
+    match (from, to) {
+        (ToN, ToB) => Some(OmnixtendPermissionChangeGrow::NtoB),
+        (ToN, ToT) => Some(OmnixtendPermissionChangeGrow::NtoT),
+        (ToB, ToT) => Some(OmnixtendPermissionChangeGrow::BtoT),
+        _ => None,
+    }
+}
+
+fn shrink_param(
+    from: OmnixtendPermissionChangeCap,
+    to: OmnixtendPermissionChangeCap,
+) -> Option<u8> {
+    use OmnixtendPermissionChangeCap::*;
+    match (from, to) {
+        (ToT, ToB) => Some(OmnixtendPermissionChangePrune::TtoB as u8),
+        (ToT, ToN) => Some(OmnixtendPermissionChangePrune::TtoN as u8),
+        (ToB, ToN) => Some(OmnixtendPermissionChangePrune::BtoN as u8),
+        (ToT, ToT) => Some(OmnixtendPermissionChangeReport::TtoT as u8),
+        (ToB, ToB) => Some(OmnixtendPermissionChangeReport::BtoB as u8),
+        (ToN, ToN) => Some(OmnixtendPermissionChangeReport::NtoN as u8),
+        _ => None,
+    }
+}
@@ -0,0 +1,327 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::time::Duration;
+
+use crate::{
+    ethernet::{VlanTag, ETHERNET_HEADER_SIZE},
+    tick::Liveness,
+    transport::Transport,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Frame size has to be between {} and {} bytes: {}",
+        MIN_MTU,
+        MAX_MTU,
+        mtu
+    ))]
+    InvalidMtu { mtu: usize },
+
+    #[snafu(display(
+        "Number of TileLink messages per frame has to be between 1 and 64: {}",
+        messages
+    ))]
+    InvalidMessagesPerFrame { messages: usize },
+
+    #[snafu(display(
+        "Number of sources has to be between 1 and {}: {}",
+        MAX_SOURCES,
+        sources
+    ))]
+    InvalidSources { sources: usize },
+
+    #[snafu(display("VLAN ID has to be between 1 and 4094: {}", id))]
+    InvalidVlanId { id: u16 },
+
+    #[snafu(display("VLAN priority has to be between 0 and 7: {}", pcp))]
+    InvalidVlanPriority { pcp: u8 },
+
+    #[snafu(display(
+        "Number of virtual channels has to be between 1 and {}: {}",
+        MAX_VIRTUAL_CHANNELS,
+        vcs
+    ))]
+    InvalidVirtualChannels { vcs: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// EtherType of OmniXtend frames.
+pub const OMNIXTEND_ETHER_TYPE: u16 = 0xAAAA;
+
+/// Smallest frame holding the headers, a two flit message and the mask.
+pub const MIN_MTU: usize = 64;
+
+/// Largest jumbo frame.
+pub const MAX_MTU: usize = 9216;
+
+/// Frame size of endpoints built with `MAXIMUM_PACKET_SIZE=1500`. Passes networks without jumbo
+/// frame support.
+pub const STANDARD_MTU: usize = 1500;
+
+/// Ethernet header, OmniXtend header and mask around the TileLink messages of a frame.
+pub const FRAME_OVERHEAD: usize = ETHERNET_HEADER_SIZE + 8 + 8;
+
+/// Sources are encoded in 26 bits, but every source needs a slot for its pending operation.
+pub const MAX_SOURCES: usize = 1 << 16;
+
+/// The VC field of the OmniXtend header has 3 bits.
+pub const MAX_VIRTUAL_CHANNELS: usize = 8;
+
+/// Protocol parameters of a connection. Used by `Connection`, `Operations` and `Tick` so both
+/// sides can be matched with endpoints built with different parameters, e.g.,
+/// `MAXIMUM_PACKET_SIZE` and `MAXIMUM_TL_PER_FRAME`. The defaults match the values used before
+/// the parameters became configurable.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    compat_mode: bool,
+    mtu: usize,
+    min_frame_size: usize,
+    ether_type: u16,
+    vlan: Option<VlanTag>,
+    send_credits: [usize; 5],
+    receive_credits: [usize; 5],
+    max_messages_per_frame: usize,
+    sources: usize,
+    virtual_channels: usize,
+    ack_only_timeout: Duration,
+    resend_timeout: Duration,
+    cycle: Duration,
+    heartbeat: Option<Duration>,
+    liveness: Option<Liveness>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            compat_mode: false,
+            mtu: 9000,
+            min_frame_size: 70,
+            ether_type: OMNIXTEND_ETHER_TYPE,
+            vlan: None,
+            send_credits: [128; 5],
+            receive_credits: [1 << 28; 5],
+            max_messages_per_frame: 64,
+            sources: 255,
+            virtual_channels: 1,
+            ack_only_timeout: Duration::from_millis(1),
+            resend_timeout: Duration::from_millis(100),
+            cycle: Duration::from_micros(1),
+            heartbeat: Some(Duration::from_secs(1)),
+            liveness: None,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Uses the OmniXtend 1.0.3 protocol without connection management.
+    pub fn with_compat_mode(self, compat_mode: bool) -> Self {
+        ConnectionConfig {
+            compat_mode,
+            ..self
+        }
+    }
+
+    /// Sets the maximum size of a frame in bytes, including the Ethernet header.
+    pub fn with_mtu(self, mtu: usize) -> Result<Self> {
+        if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+            Err(Error::InvalidMtu { mtu })?;
+        }
+        Ok(ConnectionConfig { mtu, ..self })
+    }
+
+    /// Limits frames to the standard Ethernet size, see `STANDARD_MTU`.
+    pub fn with_standard_frames(self) -> Self {
+        ConnectionConfig {
+            mtu: STANDARD_MTU,
+            ..self
+        }
+    }
+
+    /// Lowers the frame size to the largest frame `transport` can send, if it knows the MTU of
+    /// its interface. Keeps jumbo frames from being dropped by an interface without support.
+    pub fn with_transport_mtu(self, transport: &dyn Transport) -> Result<Self> {
+        match transport.max_frame_size() {
+            Some(size) if size < self.mtu => self.with_mtu(size),
+            _ => Ok(self),
+        }
+    }
+
+    /// Sets the size shorter Ethernet frames are padded to.
+    pub fn with_min_frame_size(self, min_frame_size: usize) -> Self {
+        ConnectionConfig {
+            min_frame_size,
+            ..self
+        }
+    }
+
+    pub fn with_ether_type(self, ether_type: u16) -> Self {
+        ConnectionConfig { ether_type, ..self }
+    }
+
+    /// Tags sent frames with VLAN `id` and priority `pcp`. Received frames tagged with another
+    /// VLAN are dropped, untagged frames are accepted as the interface might strip the tag. The
+    /// tag does not count against the MTU.
+    pub fn with_vlan(self, id: u16, pcp: u8) -> Result<Self> {
+        if !(1..=4094).contains(&id) {
+            Err(Error::InvalidVlanId { id })?;
+        }
+        if pcp > 7 {
+            Err(Error::InvalidVlanPriority { pcp })?;
+        }
+        Ok(ConnectionConfig {
+            vlan: Some(VlanTag { id, pcp }),
+            ..self
+        })
+    }
+
+    /// Sets the credits assumed per channel, index 0 holds channel A, before the peer has sent
+    /// any. Ignored in compat mode, which starts without credits.
+    pub fn with_send_credits(self, send_credits: [usize; 5]) -> Self {
+        ConnectionConfig {
+            send_credits,
+            ..self
+        }
+    }
+
+    /// Sets the credits handed out to the peer per channel.
+    pub fn with_receive_credits(self, receive_credits: [usize; 5]) -> Self {
+        ConnectionConfig {
+            receive_credits,
+            ..self
+        }
+    }
+
+    pub fn with_max_messages_per_frame(self, messages: usize) -> Result<Self> {
+        if !(1..=64).contains(&messages) {
+            Err(Error::InvalidMessagesPerFrame { messages })?;
+        }
+        Ok(ConnectionConfig {
+            max_messages_per_frame: messages,
+            ..self
+        })
+    }
+
+    /// Sets the number of operations that can wait for a response at the same time.
+    pub fn with_sources(self, sources: usize) -> Result<Self> {
+        if !(1..=MAX_SOURCES).contains(&sources) {
+            Err(Error::InvalidSources { sources })?;
+        }
+        Ok(ConnectionConfig { sources, ..self })
+    }
+
+    /// Sets the number of virtual channels. Every virtual channel has its own sequence numbers,
+    /// resend buffer and credits, starting with the credits configured above, so a lost frame or
+    /// missing credits on one channel do not hold back the others. Connection management only
+    /// uses VC 0, the peer has to support at least as many virtual channels.
+    pub fn with_virtual_channels(self, vcs: usize) -> Result<Self> {
+        if !(1..=MAX_VIRTUAL_CHANNELS).contains(&vcs) {
+            Err(Error::InvalidVirtualChannels { vcs })?;
+        }
+        Ok(ConnectionConfig {
+            virtual_channels: vcs,
+            ..self
+        })
+    }
+
+    /// Sets the timing parameters of `Tick`, see `Tick::new`.
+    pub fn with_timing(
+        self,
+        ack_only_timeout: Duration,
+        resend_timeout: Duration,
+        cycle: Duration,
+        heartbeat: Option<Duration>,
+    ) -> Self {
+        ConnectionConfig {
+            ack_only_timeout,
+            resend_timeout,
+            cycle,
+            heartbeat,
+            ..self
+        }
+    }
+
+    pub fn with_liveness(self, liveness: Liveness) -> Self {
+        ConnectionConfig {
+            liveness: Some(liveness),
+            ..self
+        }
+    }
+
+    pub fn compat_mode(&self) -> bool {
+        self.compat_mode
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Largest TileLink message that fits into a single frame.
+    pub fn max_message_size(&self) -> usize {
+        self.mtu - FRAME_OVERHEAD
+    }
+
+    pub fn min_frame_size(&self) -> usize {
+        self.min_frame_size
+    }
+
+    pub fn ether_type(&self) -> u16 {
+        self.ether_type
+    }
+
+    pub fn vlan(&self) -> Option<VlanTag> {
+        self.vlan
+    }
+
+    /// Credits a new connection starts with.
+    pub fn send_credits(&self) -> [usize; 5] {
+        if self.compat_mode {
+            [0; 5]
+        } else {
+            self.send_credits
+        }
+    }
+
+    pub fn receive_credits(&self) -> [usize; 5] {
+        self.receive_credits
+    }
+
+    pub fn max_messages_per_frame(&self) -> usize {
+        self.max_messages_per_frame
+    }
+
+    pub fn sources(&self) -> usize {
+        self.sources
+    }
+
+    pub fn virtual_channels(&self) -> usize {
+        self.virtual_channels
+    }
+
+    pub fn ack_only_timeout(&self) -> Duration {
+        self.ack_only_timeout
+    }
+
+    pub fn resend_timeout(&self) -> Duration {
+        self.resend_timeout
+    }
+
+    pub fn cycle(&self) -> Duration {
+        self.cycle
+    }
+
+    pub fn heartbeat(&self) -> Option<Duration> {
+        self.heartbeat
+    }
+
+    pub fn liveness(&self) -> Option<Liveness> {
+        self.liveness
+    }
+}
@@ -6,6 +6,8 @@
     Author: Jaco Hofmann (jaco.hofmann@wdc.com)
 */
 
+use crate::config::ConnectionConfig;
+use crate::ethernet::{self, EthernetFrame, VLAN_TAG_SIZE};
 use crate::omnixtend::MutableOmnixtendPacket;
 use crate::omnixtend::OmnixtendPacket;
 use crate::tilelink_messages::OmnixtendChannel;
@@ -14,13 +16,8 @@ use crate::{credits::Credits, sequence_number::SequenceNumber};
 use crossbeam::{atomic::AtomicCell, queue::SegQueue, utils::Backoff};
 use parking_lot::Mutex;
 use parking_lot::RwLock;
-use pnet::packet::ethernet::EthernetPacket;
-use pnet::packet::MutablePacket;
 use pnet::packet::Packet;
-use pnet::{
-    packet::ethernet::{EtherType, MutableEthernetPacket},
-    util::MacAddr,
-};
+use pnet::{packet::ethernet::EtherType, util::MacAddr};
 use std::collections::VecDeque;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
@@ -42,6 +39,9 @@ pub enum Error {
     #[snafu(display("[PACKET PARSE] Not OX EthType: {:?}", t))]
     ParseEthType { t: EtherType },
 
+    #[snafu(display("[PACKET PARSE] Not my VLAN: {} expected {}", id, expected))]
+    ParseVlan { id: u16, expected: u16 },
+
     #[snafu(display(
         "[PACKET PARSE] Out of order packet -> Got {} expected {}",
         got,
@@ -52,6 +52,15 @@ pub enum Error {
     #[snafu(display("[PACKET SEND] Trying to send on idle connection."))]
     SendOnIdle {},
 
+    #[snafu(display("[PACKET SEND] Connection failed, the peer stopped responding."))]
+    ConnectionFailed {},
+
+    #[snafu(display("[PACKET SEND] VC {} is not used before the connection is open.", vc))]
+    NotOpened { vc: u8 },
+
+    #[snafu(display("Virtual channel {} not configured, there are {}.", vc, vcs))]
+    InvalidVirtualChannel { vc: u8, vcs: usize },
+
     #[snafu(display("[PACKET SEND] Previous packet not sent."))]
     PacketNotSent {},
 
@@ -64,6 +73,16 @@ pub enum Error {
     #[snafu(display("Received invalid ethernet packet."))]
     NotEthernetPacket {},
 
+    #[snafu(display("Received invalid OmniXtend packet."))]
+    NotOmnixtendPacket {},
+
+    #[snafu(display(
+        "[PACKET PARSE] Ack {} beyond the {} packets waiting for an ack",
+        ack,
+        outstanding
+    ))]
+    InvalidAck { ack: u32, outstanding: usize },
+
     #[snafu(display("Timeout: Could not close connection after {} ms.", timeout.as_millis()))]
     ConnectionCloseTimeout { timeout: Duration },
 }
@@ -79,6 +98,17 @@ pub enum ConnectionState {
     ClosedByHost,
     ClosedByHostIndicated,
     ClosedByClient,
+    /// The peer stopped responding, see `Tick::with_liveness`. Nothing is sent anymore.
+    Failed,
+}
+
+/// How OmniXtend packets are carried between the two sides of a connection.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Encapsulation {
+    /// Ethernet frames with the configured EtherType, 0xAAAA by default, addressed by MAC.
+    Ethernet,
+    /// UDP datagrams without Ethernet header. Addressing is done by the transport.
+    Udp,
 }
 
 pub struct ConnectionStatus {
@@ -116,10 +146,85 @@ impl ConnectionStatus {
     }
 }
 
+/// Snapshot of the counters of a `Connection`, see `Connection::stats`. Per channel values are
+/// indexed by channel, i.e., index 0 holds channel A.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Frames handed out for sending, including resent frames.
+    pub frames_sent: u64,
+    /// Valid OmniXtend frames received, including frames dropped as out of order.
+    pub frames_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: [u64; 5],
+    /// TileLink messages received in sequence.
+    pub messages_received: [u64; 5],
+    pub naks_sent: u64,
+    pub naks_received: u64,
+    /// Number of times the resend buffer was sent again.
+    pub resends: u64,
+    pub frames_resent: u64,
+    /// Replicated or out of sequence frames that were dropped.
+    pub out_of_order_drops: u64,
+    /// Number of times an operation had to wait for send credits.
+    pub credit_stalls: [u64; 5],
+    /// Frames waiting for an ack at the time of the snapshot.
+    pub resend_buffer_depth: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: [AtomicU64; 5],
+    messages_received: [AtomicU64; 5],
+    naks_sent: AtomicU64,
+    naks_received: AtomicU64,
+    resends: AtomicU64,
+    frames_resent: AtomicU64,
+    out_of_order_drops: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, v: usize) {
+        counter.fetch_add(v as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a message on the channel encoded in its header flit.
+    fn add_message(counters: &[AtomicU64; 5], header: u64) {
+        let chan = ((header >> 60) & 0b111) as usize;
+        if (1..=counters.len()).contains(&chan) {
+            Self::add(&counters[chan - 1], 1);
+        }
+    }
+
+    fn read(&self, f: impl Fn(&AtomicU64) -> u64) -> ConnectionStats {
+        ConnectionStats {
+            frames_sent: f(&self.frames_sent),
+            frames_received: f(&self.frames_received),
+            bytes_sent: f(&self.bytes_sent),
+            bytes_received: f(&self.bytes_received),
+            messages_sent: self.messages_sent.each_ref().map(&f),
+            messages_received: self.messages_received.each_ref().map(&f),
+            naks_sent: f(&self.naks_sent),
+            naks_received: f(&self.naks_received),
+            resends: f(&self.resends),
+            frames_resent: f(&self.frames_resent),
+            out_of_order_drops: f(&self.out_of_order_drops),
+            ..Default::default()
+        }
+    }
+}
+
 type AtomicConnectionState = AtomicCell<ConnectionState>;
 type AtomicInstant = AtomicCell<Instant>;
+type FailureHandler = Box<dyn Fn() + Send + Sync>;
 
-pub struct Connection {
+/// Sequence numbers, resend buffer and credits of a single virtual channel.
+struct VirtualChannel {
+    vc: u8,
     packet_data: Mutex<Option<Vec<u8>>>,
     resend_data: SegQueue<Vec<u8>>,
     resend_buffer: RwLock<VecDeque<Vec<u8>>>,
@@ -131,28 +236,16 @@ pub struct Connection {
     first_in_resend: SequenceNumber,
     credits_send: Credits,
     credits_receive: Credits,
-    connection_state: AtomicConnectionState,
-    id: u8,
-    compat_mode: bool,
-    my_mac: RwLock<MacAddr>,
-    other_mac: RwLock<MacAddr>,
-    last_message_received_at: AtomicInstant,
-    last_message_sent_at: AtomicInstant,
-    ticks: AtomicU64,
+    last_progress_at: AtomicInstant,
     last_ack_status: AtomicBool,
     send_outstanding: AtomicBool,
     resend_outstanding: AtomicBool,
-    naks: AtomicU64,
 }
 
-impl Connection {
-    pub fn new(compat_mode: bool, id: u8, my_mac: MacAddr, other_mac: MacAddr) -> Self {
-        let default_credits = if compat_mode { 0 } else { 128 };
-        let default_credits_receive = 1 << 28;
-
-        Connection {
-            compat_mode,
-            id,
+impl VirtualChannel {
+    fn new(vc: u8, config: &ConnectionConfig) -> Self {
+        VirtualChannel {
+            vc,
             packet_data: Mutex::new(None),
             resend_data: SegQueue::new(),
             resend_buffer: RwLock::new(VecDeque::new()),
@@ -162,28 +255,121 @@ impl Connection {
             last_rx_seq: SequenceNumber::new(SequenceNumber::max()),
             we_acked: SequenceNumber::new(SequenceNumber::max()),
             first_in_resend: SequenceNumber::new(SequenceNumber::max()),
-            credits_receive: Credits::new(default_credits_receive),
-            credits_send: Credits::new(default_credits),
+            credits_send: Credits::with_channels(config.send_credits()).with_vc(vc),
+            credits_receive: Credits::with_channels(config.receive_credits()).with_vc(vc),
+            last_progress_at: AtomicInstant::new(Instant::now()),
+            last_ack_status: AtomicBool::new(false),
+            // VC 0 starts by opening the connection
+            send_outstanding: AtomicBool::new(vc == 0),
+            resend_outstanding: AtomicBool::new(false),
+        }
+    }
+}
+
+pub struct Connection {
+    vcs: Vec<VirtualChannel>,
+    connection_state: AtomicConnectionState,
+    id: u8,
+    config: ConnectionConfig,
+    encapsulation: Encapsulation,
+    my_mac: RwLock<MacAddr>,
+    other_mac: RwLock<MacAddr>,
+    last_message_received_at: AtomicInstant,
+    last_message_sent_at: AtomicInstant,
+    ticks: AtomicU64,
+    counters: Counters,
+    on_failure: Mutex<Option<FailureHandler>>,
+}
+
+impl Connection {
+    pub fn new(compat_mode: bool, id: u8, my_mac: MacAddr, other_mac: MacAddr) -> Self {
+        Self::with_config(
+            ConnectionConfig::default().with_compat_mode(compat_mode),
+            id,
+            my_mac,
+            other_mac,
+        )
+    }
+
+    /// Creates a connection exchanging UDP payloads instead of Ethernet frames.
+    pub fn new_udp(compat_mode: bool, id: u8) -> Self {
+        Self::udp_with_config(
+            ConnectionConfig::default().with_compat_mode(compat_mode),
+            id,
+        )
+    }
+
+    /// Creates a connection using the protocol parameters in `config`.
+    pub fn with_config(
+        config: ConnectionConfig,
+        id: u8,
+        my_mac: MacAddr,
+        other_mac: MacAddr,
+    ) -> Self {
+        Self::with_encapsulation(config, id, my_mac, other_mac, Encapsulation::Ethernet)
+    }
+
+    pub fn udp_with_config(config: ConnectionConfig, id: u8) -> Self {
+        Self::with_encapsulation(
+            config,
+            id,
+            MacAddr::zero(),
+            MacAddr::zero(),
+            Encapsulation::Udp,
+        )
+    }
+
+    fn with_encapsulation(
+        config: ConnectionConfig,
+        id: u8,
+        my_mac: MacAddr,
+        other_mac: MacAddr,
+        encapsulation: Encapsulation,
+    ) -> Self {
+        Connection {
+            vcs: (0..config.virtual_channels())
+                .map(|vc| VirtualChannel::new(vc as u8, &config))
+                .collect(),
+            config,
+            encapsulation,
+            id,
             connection_state: AtomicConnectionState::new(ConnectionState::Idle),
             my_mac: RwLock::new(my_mac),
             other_mac: RwLock::new(other_mac),
             last_message_received_at: AtomicInstant::new(Instant::now()),
             last_message_sent_at: AtomicInstant::new(Instant::now()),
             ticks: AtomicU64::new(0),
-            last_ack_status: AtomicBool::new(false),
-            send_outstanding: AtomicBool::new(true),
-            resend_outstanding: AtomicBool::new(false),
-            naks: AtomicU64::new(0),
+            counters: Counters::default(),
+            on_failure: Mutex::new(None),
         }
     }
 
+    /// Virtual channel `vc`, if configured.
+    fn vc(&self, vc: u8) -> Result<&VirtualChannel> {
+        self.vcs
+            .get(vc as usize)
+            .ok_or(Error::InvalidVirtualChannel {
+                vc,
+                vcs: self.vcs.len(),
+            })
+    }
+
+    /// VC 0 carries the connection management.
+    fn vc0(&self) -> &VirtualChannel {
+        &self.vcs[0]
+    }
+
+    pub fn virtual_channels(&self) -> usize {
+        self.vcs.len()
+    }
+
     pub fn connection_state(&self) -> ConnectionState {
         self.connection_state.load()
     }
 
     pub fn establish_connection(&self) {
         if self.connection_state.load() == ConnectionState::Idle {
-            if self.compat_mode {
+            if self.config.compat_mode() {
                 self.connection_state.store(ConnectionState::Active);
             } else {
                 self.connection_state.store(ConnectionState::Enabled);
@@ -193,6 +379,34 @@ impl Connection {
         }
     }
 
+    /// Marks the connection as failed and notifies the failure handler. Called by `Tick` once the
+    /// liveness budget is exhausted.
+    pub fn fail(&self) {
+        if self.connection_state.swap(ConnectionState::Failed) != ConnectionState::Failed {
+            error!(
+                "Sim {}: Peer stopped responding, connection failed.",
+                self.id
+            );
+            if let Some(f) = self.on_failure.lock().as_ref() {
+                f();
+            }
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.connection_state.load() == ConnectionState::Failed
+    }
+
+    /// Sets a handler that is called once when the connection fails. The handler runs on the
+    /// thread ticking the connection and should return quickly.
+    pub fn set_failure_handler(&self, handler: impl Fn() + Send + Sync + 'static) {
+        *self.on_failure.lock() = Some(Box::new(handler));
+    }
+
+    pub fn encapsulation(&self) -> Encapsulation {
+        self.encapsulation
+    }
+
     pub fn is_active(&self) -> bool {
         self.connection_state.load() == ConnectionState::Active
     }
@@ -203,7 +417,7 @@ impl Connection {
         {
             info!("Sim {}: Indicating closed by host state.", self.id);
             self.connection_state.store(ConnectionState::ClosedByHost);
-            self.send_outstanding.store(true, Ordering::Relaxed);
+            self.vc0().send_outstanding.store(true, Ordering::Relaxed);
         }
 
         info!("Sim {}: Waiting for connection to wind down.", self.id);
@@ -211,10 +425,14 @@ impl Connection {
     }
 
     pub fn close_connection(&self, timeout: Option<Duration>) -> Result<()> {
-        if self.compat_mode
+        if self.is_failed() {
+            Err(Error::ConnectionFailed {})?;
+        }
+
+        if self.config.compat_mode()
             || self.connection_state.load() == ConnectionState::Idle
             || (self.connection_state.load() == ConnectionState::Opened
-                && self.last_rx_seq.val() == SequenceNumber::max())
+                && self.vc0().last_rx_seq.val() == SequenceNumber::max())
             || self.connection_state.load() == ConnectionState::Enabled
         {
             error!("Cannot close connection in compat mode/idle/opened connection");
@@ -224,7 +442,7 @@ impl Connection {
         let start = Instant::now();
         let b = Backoff::new();
         // Ensure that packet 0 has been acked properly to avoid a situation where a resend contains the start flag again
-        while self.first_in_resend.val() == SequenceNumber::max() {
+        while self.vc0().first_in_resend.val() == SequenceNumber::max() {
             check_timeout(timeout, &start)?;
             b.snooze();
         }
@@ -242,46 +460,68 @@ impl Connection {
         Ok(())
     }
 
+    /// Prepares the next packet of virtual channel `vc`, filled with messages from `operations`.
+    /// Virtual channels other than VC 0 are only used once the connection is open.
     pub fn send_packet(
         &self,
+        vc: u8,
         operations: Option<&mut Vec<Vec<u8>>>,
         outstanding_requests: bool,
     ) -> Result<()> {
-        if self.connection_state.load() == ConnectionState::Idle {
+        let cstate = self.connection_state.load();
+        if cstate == ConnectionState::Idle {
             Err(Error::SendOnIdle {})?;
         }
 
-        let mut packet_data_lock = self.packet_data.lock();
+        if self.is_failed() {
+            Err(Error::ConnectionFailed {})?;
+        }
+
+        let channel = self.vc(vc)?;
+        if vc != 0 && matches!(cstate, ConnectionState::Enabled | ConnectionState::Opened) {
+            Err(Error::NotOpened { vc })?;
+        }
+
+        let mut packet_data_lock = channel.packet_data.lock();
 
         if packet_data_lock.is_some() {
             Err(Error::PacketNotSent {})?;
         }
 
-        self.send_outstanding.store(false, Ordering::Relaxed);
-        let mut wlock = self.resend_buffer.write();
-        let mut buf = vec![0; 14 + 8]; // ETH Header + TL Header
+        channel.send_outstanding.store(false, Ordering::Relaxed);
+        let mut wlock = channel.resend_buffer.write();
+        let header_len = self.header_len();
+        let mut buf = vec![0; header_len + 8]; // ETH Header + TL Header
         let _contains_data = self.put_messages(&mut buf, operations); // Used to implement AckOnly when there is no data, no credits and no other message type.
 
         let packet_len = buf.len();
 
-        let mut new_packet = self.create_eth_header(&mut buf);
-
-        let ethernet_header_string = format!("{:?}", new_packet);
+        let ethernet_header_string = if self.encapsulation == Encapsulation::Ethernet {
+            self.create_eth_header(&mut buf);
+            format!("{:?}", EthernetFrame::new(&buf))
+        } else {
+            "UDP".to_string()
+        };
 
-        let mut new_omnixtend = self.create_ox_header(&mut new_packet);
+        let mut new_omnixtend = create_ox_header(channel, &mut buf[header_len..]);
 
         new_omnixtend.set_message_type(OmnixtendMessageType::NORMAL as u8);
 
-        let cstate = self.determine_connection_state(&mut new_omnixtend, outstanding_requests);
+        let cstate = if vc == 0 {
+            self.determine_connection_state(&mut new_omnixtend, outstanding_requests)
+        } else {
+            cstate
+        };
 
-        self.set_tx_sequence(&mut new_omnixtend);
+        set_tx_sequence(channel, &mut new_omnixtend);
 
-        self.set_credit_field(&mut new_omnixtend);
+        set_credit_field(channel, &mut new_omnixtend);
 
         info!(
-                    "Sim {} @ {}: Sending in state {:?} -> {:?} Ethernet {} Omnixtend: {:?} Outstanding: {} (Size {})",
+                    "Sim {} @ {} VC {}: Sending in state {:?} -> {:?} Ethernet {} Omnixtend: {:?} Outstanding: {} (Size {})",
                     self.id,
                     self.ticks.load(Ordering::Relaxed),
+                    vc,
                     cstate,
                     self.connection_state.load(),
                     ethernet_header_string,
@@ -291,31 +531,22 @@ impl Connection {
                 );
 
         *packet_data_lock = Some(buf.clone());
-        self.we_acked.set(self.last_rx_seq.val());
+        channel.we_acked.set(channel.last_rx_seq.val());
+        // The resend timeout starts with the first packet waiting for an ack
+        if wlock.is_empty() {
+            channel.last_progress_at.store(Instant::now());
+        }
         wlock.push_back(buf);
         Ok(())
     }
 
-    fn set_credit_field(&self, new_omnixtend: &mut MutableOmnixtendPacket) {
-        let (i, v) = self.credits_receive.get_highest();
-        if i != 0 {
-            new_omnixtend.set_chan(i as u8);
-            new_omnixtend.set_credit(v as u8);
-        }
-    }
-
-    fn set_tx_sequence(&self, new_omnixtend: &mut MutableOmnixtendPacket) {
-        new_omnixtend.set_sequence_number(self.next_tx_seq.val() as u32);
-        self.next_tx_seq.incr();
-    }
-
     fn determine_connection_state(
         &self,
         new_omnixtend: &mut MutableOmnixtendPacket,
         outstanding_requests: bool,
     ) -> ConnectionState {
         let cstate = self.connection_state.load();
-        if !self.compat_mode {
+        if !self.config.compat_mode() {
             if cstate == ConnectionState::Enabled {
                 self.connection_state.store(ConnectionState::Opened);
                 new_omnixtend.set_message_type(OmnixtendMessageType::OpenConnection as u8);
@@ -331,33 +562,40 @@ impl Connection {
         cstate
     }
 
-    fn create_ox_header<'a>(
-        &self,
-        new_packet: &'a mut MutableEthernetPacket,
-    ) -> MutableOmnixtendPacket<'a> {
-        let mut new_omnixtend = MutableOmnixtendPacket::new(new_packet.payload_mut()).unwrap();
-        new_omnixtend.set_sequence_number_ack(self.last_rx_seq.val() as u32);
-        new_omnixtend.set_ack(if self.last_ack_status.load(Ordering::Relaxed) {
-            1
-        } else {
-            0
-        });
-        new_omnixtend
+    fn header_len(&self) -> usize {
+        match self.encapsulation {
+            Encapsulation::Ethernet => ethernet::header_size(self.config.vlan()),
+            Encapsulation::Udp => 0,
+        }
     }
 
-    fn create_eth_header<'a>(&self, buf: &'a mut [u8]) -> MutableEthernetPacket<'a> {
-        let mut new_packet = MutableEthernetPacket::new(buf).unwrap();
-        new_packet.set_source(*self.my_mac.read());
-        new_packet.set_destination(*self.other_mac.read());
-        new_packet.set_ethertype(EtherType(0xAAAA));
-        new_packet
+    fn vlan_tag_len(&self) -> usize {
+        match (self.encapsulation, self.config.vlan()) {
+            (Encapsulation::Ethernet, Some(_)) => VLAN_TAG_SIZE,
+            _ => 0,
+        }
+    }
+
+    fn create_eth_header(&self, buf: &mut [u8]) {
+        ethernet::write_header(
+            buf,
+            *self.my_mac.read(),
+            *self.other_mac.read(),
+            EtherType(self.config.ether_type()),
+            self.config.vlan(),
+        );
     }
 
     fn put_messages(&self, payload: &mut Vec<u8>, operations: Option<&mut Vec<Vec<u8>>>) -> bool {
         let mut mask = 0;
         let mut mask_cntr = 0;
-        let ethernet_max = 9000;
-        let ethernet_min = 70;
+        // The VLAN tag does not count against the MTU
+        let ethernet_max = self.config.mtu() + self.vlan_tag_len();
+        // Padding is only required for the minimum Ethernet frame size
+        let ethernet_min = match self.encapsulation {
+            Encapsulation::Ethernet => self.config.min_frame_size().min(ethernet_max),
+            Encapsulation::Udp => 0,
+        };
         let mut packet_len = payload.len() + 8;
 
         let mut some_data = false;
@@ -365,6 +603,7 @@ impl Connection {
         if let Some(ops) = operations {
             let mut put_in = 0;
             ops.iter()
+                .take(self.config.max_messages_per_frame())
                 .take_while(|p| {
                     space_in_packet(&mut packet_len, p, &mut mask_cntr, ethernet_max, &mut mask)
                 })
@@ -377,13 +616,19 @@ impl Connection {
                         p
                     );
                     payload.extend_from_slice(&p[..]);
+                    if let Some(h) = p.get(..8) {
+                        let header =
+                            u64::from_be_bytes(h.try_into().expect("Slice has flit size."));
+                        Counters::add_message(&self.counters.messages_sent, header);
+                    }
                     some_data = true;
                 });
             ops.drain(0..put_in);
         }
 
         if packet_len < ethernet_min {
-            let ext = ethernet_min - packet_len;
+            // Whole flits only, a smaller MTU might not fit the default minimum
+            let ext = (ethernet_min - packet_len) / 8 * 8;
             trace!(
                 "Extending packet to minimum length of {} + {} -> {}",
                 packet_len,
@@ -399,83 +644,133 @@ impl Connection {
         some_data
     }
 
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
+    }
+
+    /// Send credits of VC 0.
     pub fn credits(&self) -> &Credits {
-        &self.credits_send
+        &self.vc0().credits_send
     }
 
-    pub fn add_receive_credits(&self, chan: OmnixtendChannel, credits: usize) {
-        self.credits_receive.add(chan, credits);
+    /// Send credits of virtual channel `vc`. Operations performed with these credits are sent on
+    /// `vc`.
+    pub fn vc_credits(&self, vc: u8) -> Result<&Credits> {
+        Ok(&self.vc(vc)?.credits_send)
+    }
+
+    /// Returns credits for messages received on virtual channel `vc` to the peer.
+    pub fn add_receive_credits(&self, vc: u8, chan: OmnixtendChannel, credits: usize) {
+        match self.vc(vc) {
+            Ok(channel) => channel.credits_receive.add(chan, credits),
+            Err(e) => error!("Sim {}: {}", self.id, e),
+        }
     }
 
+    /// Returns the next packet to send. Resends go first, otherwise the virtual channels take
+    /// turns in order.
     pub fn get_packet(&self) -> Option<Vec<u8>> {
-        if let Some(p) = self.resend_data.pop() {
-            self.last_message_sent_at.store(Instant::now());
-            Some(p)
-        } else if let Some(p) = self.packet_data.lock().take() {
+        let p = self
+            .vcs
+            .iter()
+            .find_map(|c| c.resend_data.pop())
+            .or_else(|| self.vcs.iter().find_map(|c| c.packet_data.lock().take()));
+        if let Some(p) = &p {
             self.last_message_sent_at.store(Instant::now());
-            Some(p)
-        } else {
-            None
+            Counters::add(&self.counters.frames_sent, 1);
+            Counters::add(&self.counters.bytes_sent, p.len());
         }
+        p
     }
 
-    pub fn resend(&self) -> Result<()> {
-        if self.resend_buffer.read().is_empty() {
+    /// Queues the packets of virtual channel `vc` waiting for an ack for sending again.
+    pub fn resend(&self, vc: u8) -> Result<()> {
+        let channel = self.vc(vc)?;
+        if channel.resend_buffer.read().is_empty() {
             Err(Error::NoResendData {})?;
         }
 
-        if !self.resend_data.is_empty() {
+        if !channel.resend_data.is_empty() {
             Err(Error::ResendInProgress {})?;
         }
 
-        let pkts = self
+        let pkts = channel
             .resend_buffer
             .read()
             .iter()
             .cloned()
             .map(|v| {
-                self.resend_data.push(v);
+                channel.resend_data.push(v);
             })
             .count();
-        trace!("Sim {}: Adding resend of {} packets.", self.id, pkts);
-        self.resend_outstanding.store(false, Ordering::Relaxed);
+        trace!(
+            "Sim {}: Adding resend of {} packets on VC {}.",
+            self.id,
+            pkts,
+            vc
+        );
+        Counters::add(&self.counters.resends, 1);
+        Counters::add(&self.counters.frames_resent, pkts);
+        channel.resend_outstanding.store(false, Ordering::Relaxed);
         Ok(())
     }
 
-    pub fn process_packets(&self, v: &[u8]) -> Result<Vec<u8>> {
-        let packet = EthernetPacket::new(v).ok_or(Error::NotEthernetPacket {})?;
-        self.deny_wrong_mac(&packet)?;
-        deny_wrong_ethertype(self.id, &packet)?;
+    /// Parses a received packet. Returns the virtual channel it was received on and its payload,
+    /// which is empty for packets without messages.
+    pub fn process_packets(&self, v: &[u8]) -> Result<(u8, Vec<u8>)> {
+        let frame_len = v.len();
+        let v = match self.encapsulation {
+            Encapsulation::Ethernet => {
+                let frame = EthernetFrame::new(v).ok_or(Error::NotEthernetPacket {})?;
+                self.deny_wrong_mac(&frame)?;
+                deny_wrong_ethertype(self.id, self.config.ether_type(), &frame)?;
+                self.deny_wrong_vlan(&frame)?;
+                trace!("Sim {}: Received {:?}", self.id, frame);
+                frame.payload()
+            }
+            Encapsulation::Udp => v,
+        };
+
+        let omni = OmnixtendPacket::new(v).ok_or(Error::NotOmnixtendPacket {})?;
+        Counters::add(&self.counters.frames_received, 1);
+        Counters::add(&self.counters.bytes_received, frame_len);
 
-        let omni = OmnixtendPacket::new(packet.payload()).unwrap();
+        let vc = omni.get_vc();
+        let channel = self.vc(vc)?;
 
         let ack_only = omni.get_message_type() == OmnixtendMessageType::AckOnly as u8;
 
-        if omni.get_sequence_number() as i32 == self.next_rx_seq.val() {
+        if omni.get_sequence_number() as i32 == channel.next_rx_seq.val() {
+            check_ack(channel, omni.get_sequence_number_ack())?;
+
             self.last_message_received_at.store(Instant::now());
+            channel.last_progress_at.store(Instant::now());
 
             info!(
-                "Sim {}: ({}) Parsed packet (Seq {}) {:?} {:?} {}B of Payload",
+                "Sim {}: ({}) Parsed packet (VC {} Seq {}) {:?} {}B of Payload",
                 self.id,
                 self.ticks.load(Ordering::Relaxed),
-                self.next_rx_seq.val(),
-                packet,
+                vc,
+                channel.next_rx_seq.val(),
                 omni,
                 omni.payload().len()
             );
 
-            self.last_rx_seq.set(omni.get_sequence_number() as i32);
+            channel.last_rx_seq.set(omni.get_sequence_number() as i32);
 
-            self.they_acked.set(omni.get_sequence_number_ack() as i32);
+            channel
+                .they_acked
+                .set(omni.get_sequence_number_ack() as i32);
 
-            self.remove_from_resend();
+            self.remove_from_resend(channel);
 
             trace!(
-                "Sim {}: Still {} packets left to ack. (Next: {} - Ackd: {})",
+                "Sim {}: Still {} packets left to ack on VC {}. (Next: {} - Ackd: {})",
                 self.id,
-                self.next_tx_seq.diff(&self.they_acked).remainder() - 1,
-                self.next_tx_seq.val(),
-                self.they_acked.val()
+                channel.next_tx_seq.diff(&channel.they_acked).remainder() - 1,
+                vc,
+                channel.next_tx_seq.val(),
+                channel.they_acked.val()
             );
 
             if omni.get_ack() == 1 {
@@ -483,103 +778,142 @@ impl Connection {
                     "Sim {}: Got ACK for {} {}",
                     self.id,
                     omni.get_sequence_number_ack(),
-                    self.they_acked.val()
+                    channel.they_acked.val()
                 );
             } else {
                 trace!(
                     "Sim {}: Got NAK for {} {}",
                     self.id,
                     omni.get_sequence_number_ack(),
-                    self.they_acked.val()
+                    channel.they_acked.val()
                 );
-                self.indicate_nak();
+                self.indicate_nak(channel);
             }
 
             if !ack_only {
                 if omni.get_chan() > 0 {
-                    self.update_send_credits(&omni);
+                    self.update_send_credits(channel, &omni);
                 }
 
-                self.last_ack_status.store(true, Ordering::Relaxed);
-                self.next_rx_seq.incr();
+                channel.last_ack_status.store(true, Ordering::Relaxed);
+                channel.next_rx_seq.incr();
 
                 let payload = extract_payload(&omni);
+                self.count_received_messages(&payload);
 
                 self.set_connection_state_receive(&omni);
-                Ok(payload)
+                Ok((vc, payload))
             } else {
                 trace!("Sim {}: This packet is ack only.", self.id);
-                Ok(Vec::new())
+                Ok((vc, Vec::new()))
             }
-        } else if !self.next_rx_seq.cmp(omni.get_sequence_number()) {
-            self.process_replicated(ack_only, &omni)
+        } else if !channel.next_rx_seq.cmp(omni.get_sequence_number()) {
+            Counters::add(&self.counters.out_of_order_drops, 1);
+            self.process_replicated(channel, ack_only, &omni)
+                .map(|p| (vc, p))
         } else {
-            self.process_out_of_sequence(omni)
+            Counters::add(&self.counters.out_of_order_drops, 1);
+            self.process_out_of_sequence(omni).map(|p| (vc, p))
+        }
+    }
+
+    /// Counts the messages of a payload using the mask of message starts in its last flit.
+    fn count_received_messages(&self, payload: &[u8]) {
+        let (flits, mask) = match payload.len().checked_sub(8) {
+            Some(l) => payload.split_at(l),
+            None => return,
+        };
+        let mask = u64::from_be_bytes(mask.try_into().expect("Slice has flit size."));
+        for (i, flit) in flits.chunks_exact(8).enumerate().take(64) {
+            if mask & (1 << i) != 0 {
+                let header = u64::from_be_bytes(flit.try_into().expect("Slice has flit size."));
+                Counters::add_message(&self.counters.messages_received, header);
+            }
         }
     }
 
-    fn update_send_credits(&self, omni: &OmnixtendPacket) {
+    fn update_send_credits(&self, channel: &VirtualChannel, omni: &OmnixtendPacket) {
         trace!(
-            "Sim {}: Adding {} credits to channel {:?}",
+            "Sim {}: Adding {} credits to channel {:?} on VC {}",
             self.id,
             2u64.pow(omni.get_credit() as u32),
-            OmnixtendChannel::from(omni.get_chan() as u64)
+            OmnixtendChannel::from(omni.get_chan() as u64),
+            channel.vc
         );
-        self.credits_send.add(
+        channel.credits_send.add(
             OmnixtendChannel::from(omni.get_chan()),
             2usize.pow(omni.get_credit() as u32),
         );
     }
 
-    fn remove_from_resend(&self) {
-        let mut wlock = self.resend_buffer.write();
-        while self.they_acked.val() != self.first_in_resend.val() {
-            self.first_in_resend.incr();
+    fn remove_from_resend(&self, channel: &VirtualChannel) {
+        let mut wlock = channel.resend_buffer.write();
+        while channel.they_acked.val() != channel.first_in_resend.val() {
+            channel.first_in_resend.incr();
             let _first = wlock
                 .pop_front()
                 .expect("Resend buffer should not be empty...");
             trace!(
-                "Sim {}: {} left in resend buffer (First: {})",
+                "Sim {}: {} left in resend buffer of VC {} (First: {})",
                 self.id,
                 wlock.len(),
-                self.first_in_resend.val()
+                channel.vc,
+                channel.first_in_resend.val()
             );
         }
     }
 
-    fn indicate_nak(&self) {
+    fn indicate_nak(&self, channel: &VirtualChannel) {
         trace!(
-            "Sim {}: Received NAK for {}.",
-            self.they_acked.val(),
+            "Sim {}: Received NAK for {} on VC {}.",
             self.id,
+            channel.they_acked.val(),
+            channel.vc
         );
-        self.resend_outstanding.store(true, Ordering::Relaxed);
+        Counters::add(&self.counters.naks_received, 1);
+        channel.resend_outstanding.store(true, Ordering::Relaxed);
     }
 
-    fn process_replicated(&self, ack_only: bool, omni: &OmnixtendPacket) -> Result<Vec<u8>> {
+    fn process_replicated(
+        &self,
+        channel: &VirtualChannel,
+        ack_only: bool,
+        omni: &OmnixtendPacket,
+    ) -> Result<Vec<u8>> {
         if !ack_only {
             trace!(
-                "Sim {}: ({}) Sending NAK for {}",
+                "Sim {}: ({}) Sending NAK for {} on VC {}",
                 self.id,
                 self.ticks.load(Ordering::Relaxed),
-                self.next_rx_seq.val()
+                channel.next_rx_seq.val(),
+                channel.vc
             );
-            self.naks.fetch_add(1, Ordering::Relaxed);
-            self.last_ack_status.store(false, Ordering::Relaxed);
-            self.send_outstanding.store(true, Ordering::Relaxed);
+            Counters::add(&self.counters.naks_sent, 1);
+            channel.last_ack_status.store(false, Ordering::Relaxed);
+            channel.send_outstanding.store(true, Ordering::Relaxed);
             Err(Error::OutOfOrder {
                 got: omni.get_sequence_number() as usize,
-                expected: self.next_rx_seq.val() as usize,
+                expected: channel.next_rx_seq.val() as usize,
             })?
         } else {
             Ok(Vec::new())
         }
     }
 
-    fn deny_wrong_mac(&self, packet: &EthernetPacket) -> Result<()> {
-        if !self.my_mac.read().eq(&packet.get_destination()) {
+    fn deny_wrong_mac(&self, frame: &EthernetFrame) -> Result<()> {
+        if !self.my_mac.read().eq(&frame.destination()) {
             Err(Error::ParseMacError {
-                mac: packet.get_destination(),
+                mac: frame.destination(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn deny_wrong_vlan(&self, frame: &EthernetFrame) -> Result<()> {
+        if !frame.matches_vlan(self.config.vlan()) {
+            Err(Error::ParseVlan {
+                id: frame.vlan().map_or(0, |t| t.id),
+                expected: self.config.vlan().map_or(0, |t| t.id),
             })?;
         }
         Ok(())
@@ -587,11 +921,16 @@ impl Connection {
 
     fn set_connection_state_receive(&self, omni: &OmnixtendPacket) {
         let cstate = self.connection_state.load();
-        if !self.compat_mode {
+        if !self.config.compat_mode() {
             if cstate == ConnectionState::Opened {
                 self.connection_state.store(ConnectionState::Active);
             }
 
+            // Connection management is only done on VC 0
+            if omni.get_vc() != 0 {
+                return;
+            }
+
             let is_open_connection =
                 omni.get_message_type() == OmnixtendMessageType::OpenConnection as u8;
 
@@ -604,64 +943,149 @@ impl Connection {
                 self.connection_state.store(ConnectionState::Idle);
             } else if is_close_connection {
                 self.connection_state.store(ConnectionState::ClosedByClient);
-                self.send_outstanding.store(true, Ordering::Relaxed);
+                self.vc0().send_outstanding.store(true, Ordering::Relaxed);
             }
         }
     }
 
-    pub fn send_outstanding(&self) -> bool {
-        self.send_outstanding.load(Ordering::Relaxed) || self.credits_receive.any()
+    /// Whether virtual channel `vc` has to send a packet, e.g., a NAK or credits.
+    pub fn send_outstanding(&self, vc: u8) -> bool {
+        self.vc(vc)
+            .is_ok_and(|c| c.send_outstanding.load(Ordering::Relaxed) || c.credits_receive.any())
     }
 
-    pub fn ack_outstanding(&self) -> bool {
+    pub fn ack_outstanding(&self, vc: u8) -> bool {
         // First ACK or any other ACK or NAK
-        self.we_acked.val() != self.last_rx_seq.val()
+        self.vc(vc)
+            .is_ok_and(|c| c.we_acked.val() != c.last_rx_seq.val())
+    }
+
+    pub fn resend_outstanding(&self, vc: u8) -> bool {
+        self.vc(vc)
+            .is_ok_and(|c| c.resend_outstanding.load(Ordering::Relaxed))
+    }
+
+    /// Number of sent packets that have not been acked by the peer yet, on all virtual channels.
+    pub fn unacked_packets(&self) -> usize {
+        self.vcs.iter().map(|c| c.resend_buffer.read().len()).sum()
     }
 
-    pub fn resend_outstanding(&self) -> bool {
-        self.resend_outstanding.load(Ordering::Relaxed)
+    /// Last time virtual channel `vc` received a packet in sequence or, if the peer was up to
+    /// date, sent one. Packets waiting for an ack longer than the resend timeout are resent.
+    pub fn last_progress_at(&self, vc: u8) -> Instant {
+        self.vc(vc)
+            .map(|c| c.last_progress_at.load())
+            .unwrap_or_else(|_| Instant::now())
     }
 
+    /// Sequence numbers of VC 0.
     pub fn status(&self) -> ConnectionStatus {
+        let channel = self.vc0();
         ConnectionStatus {
-            rx_seq: self.next_rx_seq.val(),
-            tx_seq: self.next_tx_seq.val(),
-            they_acked: self.they_acked.val(),
-            we_acked: self.we_acked.val(),
+            rx_seq: channel.next_rx_seq.val(),
+            tx_seq: channel.next_tx_seq.val(),
+            they_acked: channel.they_acked.val(),
+            we_acked: channel.we_acked.val(),
             last_msg_in_micros: self.last_message_received_at.load().elapsed(),
             last_msg_out_micros: self.last_message_sent_at.load().elapsed(),
         }
     }
 
+    /// Snapshot of the traffic counters. The counters are updated independently of each other, so
+    /// the snapshot is not necessarily consistent while packets are processed. Counters cover all
+    /// virtual channels.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            credit_stalls: self.credit_stalls(Credits::stalls),
+            resend_buffer_depth: self.unacked_packets(),
+            ..self.counters.read(|c| c.load(Ordering::Relaxed))
+        }
+    }
+
+    /// Returns the traffic counters and sets them to zero.
+    pub fn reset_stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            credit_stalls: self.credit_stalls(Credits::reset_stalls),
+            resend_buffer_depth: self.unacked_packets(),
+            ..self.counters.read(|c| c.swap(0, Ordering::Relaxed))
+        }
+    }
+
+    fn credit_stalls(&self, f: impl Fn(&Credits) -> [u64; 5]) -> [u64; 5] {
+        self.vcs.iter().fold([0; 5], |mut sum, c| {
+            sum.iter_mut()
+                .zip(f(&c.credits_send))
+                .for_each(|(s, v)| *s += v);
+            sum
+        })
+    }
+
     pub fn last_message_received_at(&self) -> Instant {
         self.last_message_received_at.load()
     }
 
     fn process_out_of_sequence(&self, omni: OmnixtendPacket) -> Result<Vec<u8>> {
         trace!(
-            "Sim {}: Ignoring out of sequence packet {}",
+            "Sim {}: Ignoring out of sequence packet {} on VC {}",
             self.id,
-            omni.get_sequence_number()
+            omni.get_sequence_number(),
+            omni.get_vc()
         );
         Ok(Vec::new())
     }
 }
 
+fn create_ox_header<'a>(channel: &VirtualChannel, buf: &'a mut [u8]) -> MutableOmnixtendPacket<'a> {
+    let mut new_omnixtend = MutableOmnixtendPacket::new(buf).unwrap();
+    new_omnixtend.set_vc(channel.vc);
+    new_omnixtend.set_sequence_number_ack(channel.last_rx_seq.val() as u32);
+    new_omnixtend.set_ack(if channel.last_ack_status.load(Ordering::Relaxed) {
+        1
+    } else {
+        0
+    });
+    new_omnixtend
+}
+
+fn set_tx_sequence(channel: &VirtualChannel, new_omnixtend: &mut MutableOmnixtendPacket) {
+    new_omnixtend.set_sequence_number(channel.next_tx_seq.val() as u32);
+    channel.next_tx_seq.incr();
+}
+
+fn set_credit_field(channel: &VirtualChannel, new_omnixtend: &mut MutableOmnixtendPacket) {
+    let (i, v) = channel.credits_receive.get_highest();
+    if i != 0 {
+        new_omnixtend.set_chan(i as u8);
+        new_omnixtend.set_credit(v as u8);
+    }
+}
+
+// Acks for packets that have not been sent would drain the resend buffer
+fn check_ack(channel: &VirtualChannel, ack: u32) -> Result<()> {
+    let outstanding = channel.resend_buffer.read().len();
+    let acked = (ack as i32 - channel.first_in_resend.val())
+        .rem_euclid(SequenceNumber::modulus() as i32) as usize;
+    if acked > outstanding {
+        Err(Error::InvalidAck { ack, outstanding })?;
+    }
+    Ok(())
+}
+
 fn extract_payload(omni: &OmnixtendPacket) -> Vec<u8> {
     let mut payload = vec![0u8; omni.payload().len()];
     payload.copy_from_slice(omni.payload());
     payload
 }
 
-fn deny_wrong_ethertype(id: u8, packet: &EthernetPacket) -> Result<()> {
-    if !packet.get_ethertype().eq(&EtherType::new(0xAAAA)) {
+fn deny_wrong_ethertype(id: u8, ether_type: u16, frame: &EthernetFrame) -> Result<()> {
+    if !frame.ether_type().eq(&EtherType::new(ether_type)) {
         trace!(
             "Sim {}: Invalid Ether Type {:?}. Dropping.",
             id,
-            packet.get_ethertype()
+            frame.ether_type()
         );
         Err(Error::ParseEthType {
-            t: packet.get_ethertype(),
+            t: frame.ether_type(),
         })?;
     }
     Ok(())
@@ -684,7 +1108,7 @@ fn space_in_packet(
     mask: &mut u64,
 ) -> bool {
     let packet_len_new = *packet_len + p.len();
-    if *mask_cntr < 64 && packet_len_new < ethernet_max {
+    if *mask_cntr < 64 && packet_len_new <= ethernet_max {
         *packet_len = packet_len_new;
         *mask |= 1 << *mask_cntr;
         *mask_cntr += p.len() / 8;
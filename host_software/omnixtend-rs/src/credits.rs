@@ -6,39 +6,61 @@
     Author: Jaco Hofmann (jaco.hofmann@wdc.com)
 */
 
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    task::Waker,
+};
+
 use parking_lot::Mutex;
 
-use crate::tilelink_messages::OmnixtendChannel;
+use crate::{tilelink_messages::OmnixtendChannel, utils::Wakers};
 
 pub struct Credits {
     credits: [Mutex<usize>; 5],
+    wakers: Wakers,
+    stalls: [AtomicU64; 5],
+    vc: u8,
 }
 
 impl Credits {
     // Despite using AtomicUsize, this is certainly not thread safe...
 
     pub fn new(credits: usize) -> Credits {
+        Self::with_channels([credits; 5])
+    }
+
+    /// Creates credits with a different amount per channel, index 0 holds channel A.
+    pub fn with_channels(credits: [usize; 5]) -> Credits {
         Credits {
-            credits: [
-                Mutex::new(credits),
-                Mutex::new(credits),
-                Mutex::new(credits),
-                Mutex::new(credits),
-                Mutex::new(credits),
-            ],
+            credits: credits.map(Mutex::new),
+            wakers: Wakers::default(),
+            stalls: Default::default(),
+            vc: 0,
         }
     }
 
+    /// Assigns the credits to virtual channel `vc`. Operations taking these credits are sent on
+    /// that virtual channel.
+    pub fn with_vc(self, vc: u8) -> Credits {
+        Credits { vc, ..self }
+    }
+
+    pub fn vc(&self) -> u8 {
+        self.vc
+    }
+
     pub fn add(&self, chan: OmnixtendChannel, credits: usize) {
         if chan != OmnixtendChannel::INVALID {
             let mut credit = self.credits[chan as usize - 1].lock();
-            *credit += credits;
+            *credit = credit.saturating_add(credits);
             trace!(
                 "Added {} credits to channel {:?}. Channel now has {} credits.",
                 credits,
                 chan,
                 credit
             );
+            drop(credit);
+            self.wake();
         }
     }
 
@@ -62,6 +84,22 @@ impl Credits {
         }
     }
 
+    /// Records that an operation has to wait for credits on `chan`.
+    pub fn stalled(&self, chan: OmnixtendChannel) {
+        if chan != OmnixtendChannel::INVALID {
+            self.stalls[chan as usize - 1].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of stalls per channel, index 0 holds channel A.
+    pub fn stalls(&self) -> [u64; 5] {
+        self.stalls.each_ref().map(|s| s.load(Ordering::Relaxed))
+    }
+
+    pub fn reset_stalls(&self) -> [u64; 5] {
+        self.stalls.each_ref().map(|s| s.swap(0, Ordering::Relaxed))
+    }
+
     pub fn any(&self) -> bool {
         self.credits.iter().any(|x| *x.lock() != 0)
     }
@@ -89,5 +127,19 @@ impl Credits {
         for i in 0..self.credits.len() {
             *self.credits[i].lock() = *other.credits[i].lock();
         }
+        self.wake();
+    }
+
+    /// Wakes the task of future `id` the next time credits are added.
+    pub(crate) fn register_waker(&self, id: usize, waker: &Waker) {
+        self.wakers.register(id, waker);
+    }
+
+    pub(crate) fn remove_waker(&self, id: usize) {
+        self.wakers.remove(id);
+    }
+
+    fn wake(&self) {
+        self.wakers.wake();
     }
 }
@@ -0,0 +1,408 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use parking_lot::Mutex;
+
+use crate::tilelink_messages::{
+    get_permission_after_change, get_resulting_permission, OmnixtendPermissionChangeCap,
+    OmnixtendPermissionChangeGrow, OmnixtendSource,
+};
+
+/// Probe the directory wants to send to a client.
+#[derive(Debug, Clone)]
+pub struct ProbeRequest {
+    pub address: u64,
+    pub size: u8,
+    pub permissions: OmnixtendPermissionChangeCap,
+    pub block: bool,
+}
+
+#[derive(Debug)]
+struct Pending {
+    client: u8,
+    source: OmnixtendSource,
+    awaiting: HashSet<u8>,
+    grant_ack: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Line {
+    size: u8,
+    holders: HashMap<u8, OmnixtendPermissionChangeCap>,
+    pending: Option<Pending>,
+}
+
+impl Line {
+    fn new(size: u8) -> Self {
+        Line {
+            size,
+            holders: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        1 << self.size
+    }
+
+    fn pending_by_other(&self, client: u8, source: OmnixtendSource) -> bool {
+        self.pending
+            .as_ref()
+            .is_some_and(|p| p.client != client || p.source != source)
+    }
+
+    fn probes_outstanding(&self) -> bool {
+        self.pending
+            .as_ref()
+            .is_some_and(|p| !p.awaiting.is_empty())
+    }
+
+    fn is_unused(&self) -> bool {
+        self.pending.is_none()
+            && self
+                .holders
+                .values()
+                .all(|v| *v == OmnixtendPermissionChangeCap::ToN)
+    }
+}
+
+#[derive(Default)]
+struct DirectoryState {
+    lines: BTreeMap<u64, Line>,
+    max_line: u64,
+    probes: HashMap<u8, Vec<ProbeRequest>>,
+    grants: HashMap<u32, u64>,
+}
+
+impl DirectoryState {
+    fn conflicting(
+        line: &Line,
+        client: u8,
+        permissions: OmnixtendPermissionChangeCap,
+    ) -> Vec<(u8, OmnixtendPermissionChangeCap)> {
+        line.holders
+            .iter()
+            .filter(|(c, cap)| {
+                **c != client
+                    && match permissions {
+                        OmnixtendPermissionChangeCap::ToT => {
+                            **cap != OmnixtendPermissionChangeCap::ToN
+                        }
+                        _ => **cap == OmnixtendPermissionChangeCap::ToT,
+                    }
+            })
+            .map(|(c, _)| {
+                (
+                    *c,
+                    if permissions == OmnixtendPermissionChangeCap::ToT {
+                        OmnixtendPermissionChangeCap::ToN
+                    } else {
+                        OmnixtendPermissionChangeCap::ToB
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn issue_probes(
+        &mut self,
+        address: u64,
+        client: u8,
+        source: OmnixtendSource,
+        permissions: OmnixtendPermissionChangeCap,
+        block: bool,
+    ) -> bool {
+        let line = self.lines.get_mut(&address).expect("Line has to exist.");
+        let conflicts = Self::conflicting(line, client, permissions);
+        if conflicts.is_empty() {
+            return false;
+        }
+
+        let mut awaiting = HashSet::new();
+        for (c, cap) in conflicts {
+            trace!(
+                "DIRECTORY: Probing 0x{:X} of client {} to {:?} for client {}.",
+                address,
+                c,
+                cap,
+                client
+            );
+            awaiting.insert(c);
+            self.probes.entry(c).or_default().push(ProbeRequest {
+                address,
+                size: line.size,
+                permissions: cap,
+                block,
+            });
+        }
+        line.pending = Some(Pending {
+            client,
+            source,
+            awaiting,
+            grant_ack: None,
+        });
+        true
+    }
+
+    fn overlapping(&self, address: u64, len: u64) -> Vec<u64> {
+        self.lines
+            .range(address.saturating_sub(self.max_line - 1)..address.saturating_add(len))
+            .filter(|(a, l)| a.saturating_add(l.len()) > address)
+            .map(|(a, _)| *a)
+            .collect()
+    }
+
+    fn cleanup(&mut self, address: u64) {
+        if self.lines.get(&address).is_some_and(|l| l.is_unused()) {
+            self.lines.remove(&address);
+        }
+    }
+}
+
+/// Sink of grants denied without consulting the directory. Never handed out for a granted line.
+pub const DENIED_SINK: u32 = 0;
+
+/// Lowest point of coherence shared by all connections of an endpoint. Tracks the permissions
+/// every client holds on a cache line and generates the probes required before conflicting
+/// permissions can be granted. Lines are identified by their base address, clients are expected
+/// to use the same line size for a given address.
+pub struct Directory {
+    state: Mutex<DirectoryState>,
+    next_sink: AtomicU32,
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Directory {
+            state: Mutex::new(DirectoryState {
+                max_line: 1,
+                ..Default::default()
+            }),
+            next_sink: AtomicU32::new(0),
+        }
+    }
+
+    /// Tries to grant `grow` on the line at `address` to `client`. Returns the granted permission
+    /// and the sink to expect the GrantAck on, or `None` while other clients still have to be
+    /// probed. The same request has to be retried until it succeeds.
+    pub fn acquire(
+        &self,
+        client: u8,
+        source: OmnixtendSource,
+        address: u64,
+        size: u8,
+        grow: OmnixtendPermissionChangeGrow,
+        block: bool,
+    ) -> Option<(OmnixtendPermissionChangeCap, u32)> {
+        let mut state = self.state.lock();
+        let permissions = get_resulting_permission(&grow);
+
+        let line = state
+            .lines
+            .entry(address)
+            .or_insert_with(|| Line::new(size));
+        if line.pending_by_other(client, source) || line.probes_outstanding() {
+            return None;
+        }
+
+        // Grant was already issued but could not be sent yet
+        if let Some(sink) = line.pending.as_ref().and_then(|p| p.grant_ack) {
+            return Some((permissions, sink));
+        }
+
+        if line.pending.is_none() {
+            state.max_line = state.max_line.max(1 << size);
+            if state.issue_probes(address, client, source, permissions, block) {
+                return None;
+            }
+        }
+
+        let sink = self.next_sink.fetch_add(1, Ordering::Relaxed) % ((1 << 26) - 1) + 1;
+        let line = state.lines.get_mut(&address).expect("Line has to exist.");
+        line.holders.insert(client, permissions);
+        line.pending = Some(Pending {
+            client,
+            source,
+            awaiting: HashSet::new(),
+            grant_ack: Some(sink),
+        });
+        state.grants.insert(sink, address);
+        trace!(
+            "DIRECTORY: Granting {:?} on 0x{:X} to client {} (Sink {}).",
+            permissions,
+            address,
+            client,
+            sink
+        );
+        Some((permissions, sink))
+    }
+
+    /// Checks if an uncached access of `len` bytes at `address` may be performed. Cached copies
+    /// are probed to Branch for reads and to None for writes first. The same request has to be
+    /// retried until it succeeds.
+    pub fn access(
+        &self,
+        client: u8,
+        source: OmnixtendSource,
+        address: u64,
+        len: u64,
+        write: bool,
+    ) -> bool {
+        let mut state = self.state.lock();
+        let lines = state.overlapping(address, len);
+
+        if lines.iter().any(|a| {
+            let l = &state.lines[a];
+            l.pending_by_other(client, source) || l.probes_outstanding()
+        }) {
+            return false;
+        }
+
+        let permissions = if write {
+            OmnixtendPermissionChangeCap::ToT
+        } else {
+            OmnixtendPermissionChangeCap::ToB
+        };
+
+        let mut probing = false;
+        for a in lines.iter() {
+            if state.lines[a].pending.is_none() {
+                probing |= state.issue_probes(*a, client, source, permissions, true);
+            }
+        }
+
+        if probing {
+            return false;
+        }
+
+        for a in lines.iter() {
+            if let Some(l) = state.lines.get_mut(a) {
+                l.pending = None;
+            }
+            state.cleanup(*a);
+        }
+        true
+    }
+
+    pub fn grant_ack(&self, sink: u32) {
+        if sink == DENIED_SINK {
+            return;
+        }
+        let mut state = self.state.lock();
+        if let Some(address) = state.grants.remove(&sink) {
+            trace!("DIRECTORY: GrantAck for 0x{:X} (Sink {}).", address, sink);
+            if let Some(l) = state.lines.get_mut(&address) {
+                l.pending = None;
+            }
+            state.cleanup(address);
+        } else {
+            error!("DIRECTORY: GrantAck for unknown sink {}.", sink);
+        }
+    }
+
+    pub fn probe_ack(&self, client: u8, address: u64, param: u8) {
+        let mut state = self.state.lock();
+        if let Some(l) = state.lines.get_mut(&address) {
+            let permissions = get_permission_after_change(param);
+            trace!(
+                "DIRECTORY: ProbeAck of client {} for 0x{:X} -> {:?}.",
+                client,
+                address,
+                permissions
+            );
+            l.holders.insert(client, permissions);
+            if let Some(p) = l.pending.as_mut() {
+                p.awaiting.remove(&client);
+            }
+        } else {
+            error!(
+                "DIRECTORY: ProbeAck of client {} for unknown line 0x{:X}.",
+                client, address
+            );
+        }
+    }
+
+    pub fn release(&self, client: u8, address: u64, param: u8) {
+        let mut state = self.state.lock();
+        if let Some(l) = state.lines.get_mut(&address) {
+            let permissions = get_permission_after_change(param);
+            trace!(
+                "DIRECTORY: Release of client {} for 0x{:X} -> {:?}.",
+                client,
+                address,
+                permissions
+            );
+            l.holders.insert(client, permissions);
+        }
+        state.cleanup(address);
+    }
+
+    pub fn take_probes(&self, client: u8) -> Vec<ProbeRequest> {
+        self.state.lock().probes.remove(&client).unwrap_or_default()
+    }
+
+    /// Requeues a probe that could not be sent to `client`.
+    pub fn retry_probe(&self, client: u8, probe: ProbeRequest) {
+        self.state
+            .lock()
+            .probes
+            .entry(client)
+            .or_default()
+            .push(probe);
+    }
+
+    pub fn holders(&self, address: u64) -> Vec<(u8, OmnixtendPermissionChangeCap)> {
+        self.state
+            .lock()
+            .lines
+            .get(&address)
+            .map_or_else(Vec::new, |l| {
+                l.holders
+                    .iter()
+                    .filter(|(_, cap)| **cap != OmnixtendPermissionChangeCap::ToN)
+                    .map(|(c, cap)| (*c, *cap))
+                    .collect()
+            })
+    }
+
+    /// Drops all state of a client, e.g., after its connection was closed.
+    pub fn remove_client(&self, client: u8) {
+        let mut state = self.state.lock();
+        state.probes.remove(&client);
+        let addresses: Vec<u64> = state.lines.keys().cloned().collect();
+        for a in addresses {
+            if let Some(l) = state.lines.get_mut(&a) {
+                l.holders.remove(&client);
+                if l.pending.as_ref().is_some_and(|p| p.client == client) {
+                    l.pending = None;
+                } else if let Some(p) = l.pending.as_mut() {
+                    p.awaiting.remove(&client);
+                }
+            }
+            state.cleanup(a);
+        }
+        let DirectoryState { lines, grants, .. } = &mut *state;
+        grants.retain(|s, a| {
+            lines
+                .get(a)
+                .and_then(|l| l.pending.as_ref())
+                .is_some_and(|p| p.grant_ack == Some(*s))
+        });
+    }
+}
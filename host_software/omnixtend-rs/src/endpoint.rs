@@ -0,0 +1,273 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use parking_lot::Mutex;
+use pnet::{packet::ethernet::EtherType, util::MacAddr};
+use snafu::ResultExt;
+
+use crate::{
+    config::ConnectionConfig,
+    connection::{Connection, ConnectionState, Encapsulation},
+    directory::Directory,
+    ethernet::EthernetFrame,
+    omnixtend::OmnixtendPacket,
+    operations::Operations,
+    responder::{Memory, Responder},
+    tick::Tick,
+    tilelink_messages::OmnixtendMessageType,
+    utils::process_request_packet,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Received invalid ethernet packet."))]
+    NotEthernetPacket {},
+
+    #[snafu(display("Received invalid OmniXtend packet."))]
+    NotOmnixtendPacket {},
+
+    #[snafu(display("Not my MAC: {:?}", mac))]
+    WrongMac { mac: MacAddr },
+
+    #[snafu(display("Not OX EthType: {:?}", t))]
+    WrongEthType { t: EtherType },
+
+    #[snafu(display("Not my VLAN: {}", id))]
+    WrongVlan { id: u16 },
+
+    #[snafu(display("No open connection for {:?}", mac))]
+    NoConnection { mac: MacAddr },
+
+    #[snafu(display("Omnixtend-rs error: {}", source))]
+    Omnixtend { source: crate::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+pub struct EndpointConnection {
+    connection: Connection,
+    operations: Operations,
+    responder: Responder,
+    tick: Mutex<Tick>,
+    removed: AtomicBool,
+}
+
+impl EndpointConnection {
+    fn new(
+        config: &ConnectionConfig,
+        encapsulation: Encapsulation,
+        id: u8,
+        my_mac: MacAddr,
+        other_mac: MacAddr,
+        memory: Arc<Memory>,
+        directory: Arc<Directory>,
+    ) -> Self {
+        let connection = match encapsulation {
+            Encapsulation::Ethernet => {
+                Connection::with_config(config.clone(), id, my_mac, other_mac)
+            }
+            Encapsulation::Udp => Connection::udp_with_config(config.clone(), id),
+        };
+        if config.compat_mode() {
+            connection.establish_connection();
+        }
+        EndpointConnection {
+            connection,
+            operations: Operations::with_config(config),
+            responder: Responder::new(id, memory, directory),
+            tick: Mutex::new(Tick::with_config(config)),
+            removed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn operations(&self) -> &Operations {
+        &self.operations
+    }
+
+    pub fn responder(&self) -> &Responder {
+        &self.responder
+    }
+
+    /// Drops everything the directory knows about this connection once.
+    fn remove_from_directory(&self) {
+        if !self.removed.swap(true, Ordering::Relaxed) {
+            self.responder
+                .directory()
+                .remove_client(self.responder.id());
+        }
+    }
+}
+
+/// Software OmniXtend endpoint. Accepts connections from any requester addressing `my_mac` and
+/// serves their requests from a shared memory. Cached copies of all connections are kept coherent
+/// through a shared directory. With UDP encapsulation the transport only talks to a single remote,
+/// so there is at most one connection.
+pub struct Endpoint {
+    my_mac: MacAddr,
+    config: ConnectionConfig,
+    encapsulation: Encapsulation,
+    memory: Arc<Memory>,
+    directory: Arc<Directory>,
+    connections: DashMap<MacAddr, Arc<EndpointConnection>>,
+    next_id: AtomicU8,
+}
+
+impl Endpoint {
+    pub fn new(compat_mode: bool, my_mac: MacAddr, memory: Arc<Memory>) -> Self {
+        Self::with_config(
+            ConnectionConfig::default().with_compat_mode(compat_mode),
+            my_mac,
+            memory,
+        )
+    }
+
+    pub fn new_udp(compat_mode: bool, memory: Arc<Memory>) -> Self {
+        Self::udp_with_config(
+            ConnectionConfig::default().with_compat_mode(compat_mode),
+            memory,
+        )
+    }
+
+    /// Creates an endpoint whose connections use the protocol parameters in `config`.
+    pub fn with_config(config: ConnectionConfig, my_mac: MacAddr, memory: Arc<Memory>) -> Self {
+        Self::with_encapsulation(config, Encapsulation::Ethernet, my_mac, memory)
+    }
+
+    pub fn udp_with_config(config: ConnectionConfig, memory: Arc<Memory>) -> Self {
+        Self::with_encapsulation(config, Encapsulation::Udp, MacAddr::zero(), memory)
+    }
+
+    fn with_encapsulation(
+        config: ConnectionConfig,
+        encapsulation: Encapsulation,
+        my_mac: MacAddr,
+        memory: Arc<Memory>,
+    ) -> Self {
+        Endpoint {
+            my_mac,
+            config,
+            encapsulation,
+            memory,
+            directory: Arc::new(Directory::new()),
+            connections: DashMap::new(),
+            next_id: AtomicU8::new(0),
+        }
+    }
+
+    pub fn memory(&self) -> &Arc<Memory> {
+        &self.memory
+    }
+
+    pub fn directory(&self) -> &Arc<Directory> {
+        &self.directory
+    }
+
+    pub fn connections(&self) -> &DashMap<MacAddr, Arc<EndpointConnection>> {
+        &self.connections
+    }
+
+    pub fn process_packet(&self, v: &[u8]) -> Result<()> {
+        let (mac, is_open) = match self.encapsulation {
+            Encapsulation::Ethernet => {
+                let frame = EthernetFrame::new(v).ok_or(Error::NotEthernetPacket {})?;
+                if frame.destination() != self.my_mac {
+                    Err(Error::WrongMac {
+                        mac: frame.destination(),
+                    })?;
+                }
+                if frame.ether_type() != EtherType(self.config.ether_type()) {
+                    Err(Error::WrongEthType {
+                        t: frame.ether_type(),
+                    })?;
+                }
+                if !frame.matches_vlan(self.config.vlan()) {
+                    Err(Error::WrongVlan {
+                        id: frame.vlan().map_or(0, |t| t.id),
+                    })?;
+                }
+                (frame.source(), is_open_connection(frame.payload())?)
+            }
+            Encapsulation::Udp => (MacAddr::zero(), is_open_connection(v)?),
+        };
+
+        let c = match self.connections.entry(mac) {
+            Entry::Occupied(mut o) => {
+                // A closed or failed connection is replaced to start over with fresh sequence numbers
+                if is_open
+                    && matches!(
+                        o.get().connection.connection_state(),
+                        ConnectionState::Idle | ConnectionState::Failed
+                    )
+                {
+                    info!("ENDPOINT: Reopening connection from {}.", mac);
+                    o.get().remove_from_directory();
+                    o.insert(self.new_connection(mac));
+                }
+                o.get().clone()
+            }
+            Entry::Vacant(v) => {
+                if !is_open && !self.config.compat_mode() {
+                    Err(Error::NoConnection { mac })?;
+                }
+                info!("ENDPOINT: New connection from {}.", mac);
+                v.insert(self.new_connection(mac)).clone()
+            }
+        };
+
+        process_request_packet(v, &c.connection, &c.responder).context(OmnixtendSnafu)
+    }
+
+    fn new_connection(&self, other_mac: MacAddr) -> Arc<EndpointConnection> {
+        Arc::new(EndpointConnection::new(
+            &self.config,
+            self.encapsulation,
+            self.next_id.fetch_add(1, Ordering::Relaxed),
+            self.my_mac,
+            other_mac,
+            self.memory.clone(),
+            self.directory.clone(),
+        ))
+    }
+
+    pub fn get_packet(&self) -> Option<Vec<u8>> {
+        self.connections
+            .iter()
+            .find_map(|c| c.value().connection.get_packet())
+    }
+
+    pub fn tick(&self) {
+        // The map is not locked while ticking, the receiving thread might need it to return
+        // credits
+        let connections: Vec<Arc<EndpointConnection>> =
+            self.connections.iter().map(|c| c.value().clone()).collect();
+        for c in connections {
+            match c.connection.connection_state() {
+                ConnectionState::Idle | ConnectionState::Failed => c.remove_from_directory(),
+                _ => c
+                    .tick
+                    .lock()
+                    .tick_responder(&c.operations, &c.connection, &c.responder),
+            }
+        }
+    }
+}
+
+fn is_open_connection(v: &[u8]) -> Result<bool> {
+    let omni = OmnixtendPacket::new(v).ok_or(Error::NotOmnixtendPacket {})?;
+    Ok(omni.get_message_type() == OmnixtendMessageType::OpenConnection as u8)
+}
@@ -0,0 +1,145 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::fmt;
+
+use pnet::{
+    packet::{
+        ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket},
+        vlan::{ClassOfService, MutableVlanPacket, VlanPacket},
+        MutablePacket,
+    },
+    util::MacAddr,
+};
+
+/// Ethernet II header without 802.1Q tag.
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// 802.1Q tag between the source MAC and the EtherType.
+pub const VLAN_TAG_SIZE: usize = 4;
+
+/// 802.1Q tag of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    /// VLAN ID, 1 to 4094.
+    pub id: u16,
+    /// Priority code point, 0 to 7.
+    pub pcp: u8,
+}
+
+/// Received Ethernet frame. If the frame carries an 802.1Q tag, `ether_type` and `payload` are
+/// the ones following the tag.
+pub struct EthernetFrame<'a> {
+    frame: &'a [u8],
+    source: MacAddr,
+    destination: MacAddr,
+    ether_type: EtherType,
+    vlan: Option<VlanTag>,
+    header_len: usize,
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn new(frame: &'a [u8]) -> Option<Self> {
+        let packet = EthernetPacket::new(frame)?;
+        let (ether_type, vlan, header_len) = if packet.get_ethertype() == EtherTypes::Vlan {
+            let tag = VlanPacket::new(&frame[ETHERNET_HEADER_SIZE..])?;
+            (
+                tag.get_ethertype(),
+                Some(VlanTag {
+                    id: tag.get_vlan_identifier(),
+                    pcp: tag.get_priority_code_point().0,
+                }),
+                ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE,
+            )
+        } else {
+            (packet.get_ethertype(), None, ETHERNET_HEADER_SIZE)
+        };
+        Some(EthernetFrame {
+            frame,
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            ether_type,
+            vlan,
+            header_len,
+        })
+    }
+
+    pub fn source(&self) -> MacAddr {
+        self.source
+    }
+
+    pub fn destination(&self) -> MacAddr {
+        self.destination
+    }
+
+    pub fn ether_type(&self) -> EtherType {
+        self.ether_type
+    }
+
+    pub fn vlan(&self) -> Option<VlanTag> {
+        self.vlan
+    }
+
+    /// Checks the tag against the VLAN `expected` on the link. Untagged frames are accepted, the
+    /// interface might have removed the tag already.
+    pub fn matches_vlan(&self, expected: Option<VlanTag>) -> bool {
+        match (self.vlan, expected) {
+            (Some(tag), Some(expected)) => tag.id == expected.id,
+            _ => true,
+        }
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.frame[self.header_len..]
+    }
+}
+
+impl fmt::Debug for EthernetFrame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EthernetFrame")
+            .field("source", &self.source)
+            .field("destination", &self.destination)
+            .field("ether_type", &self.ether_type)
+            .field("vlan", &self.vlan)
+            .field("payload_len", &self.payload().len())
+            .finish()
+    }
+}
+
+/// Size of the header written by `write_header`.
+pub fn header_size(vlan: Option<VlanTag>) -> usize {
+    match vlan {
+        Some(_) => ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE,
+        None => ETHERNET_HEADER_SIZE,
+    }
+}
+
+/// Writes an Ethernet header, including an 802.1Q tag if `vlan` is set, to the start of `buf`.
+pub fn write_header(
+    buf: &mut [u8],
+    source: MacAddr,
+    destination: MacAddr,
+    ether_type: EtherType,
+    vlan: Option<VlanTag>,
+) {
+    let mut packet = MutableEthernetPacket::new(buf).expect("Buffer holds the Ethernet header.");
+    packet.set_source(source);
+    packet.set_destination(destination);
+    match vlan {
+        Some(v) => {
+            packet.set_ethertype(EtherTypes::Vlan);
+            let mut tag =
+                MutableVlanPacket::new(packet.payload_mut()).expect("Buffer holds the VLAN tag.");
+            tag.set_priority_code_point(ClassOfService::new(v.pcp));
+            tag.set_drop_eligible_indicator(0);
+            tag.set_vlan_identifier(v.id);
+            tag.set_ethertype(ether_type);
+        }
+        None => packet.set_ethertype(ether_type),
+    }
+}
@@ -12,21 +12,36 @@ extern crate snafu;
 #[macro_use]
 extern crate log;
 
+pub mod async_operations;
 pub mod cache;
 pub mod channels;
+pub mod coherence;
+pub mod config;
 pub mod connection;
 pub mod credits;
+pub mod directory;
+pub mod endpoint;
+pub mod ethernet;
 pub mod omnixtend;
 pub mod operations;
+pub mod responder;
+pub mod runner;
 mod sequence_number;
 pub mod tick;
 pub mod tilelink_messages;
+pub mod transport;
 pub mod utils;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Connection Error: {}", source))]
     ConnectionError { source: crate::connection::Error },
+
+    #[snafu(display("Invalid configuration: {}", source))]
+    ConfigError { source: crate::config::Error },
+
+    #[snafu(display("Malformed packet payload: {}", source))]
+    ChannelError { source: crate::channels::Error },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -7,28 +7,34 @@
 */
 
 use std::{
+    cmp::max,
     collections::VecDeque,
-    sync::atomic::{AtomicUsize, Ordering},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
-use crossbeam::{
-    channel::{bounded, Receiver, Sender},
-    queue::SegQueue,
-    utils::Backoff,
-};
-use parking_lot::Mutex;
+use crossbeam::{queue::SegQueue, utils::Backoff};
+use parking_lot::{Condvar, Mutex};
 
 use crate::{
+    channels::Response,
+    config::ConnectionConfig,
     credits::Credits,
     tilelink_messages::{
-        get_permission_change, ChanABCDTilelinkMessage, ChanETilelinkMessage, OmnixtendChannel,
-        OmnixtendPermissionChangeCap, OmnixtendPermissionChangeGrow, OmnixtendSource,
-        TilelinkMessage,
+        get_permission_change, ChanABCDTilelinkMessage, ChanETilelinkMessage, OmnixtendArithmetic,
+        OmnixtendChannel, OmnixtendHint, OmnixtendLogical, OmnixtendPermissionChangeCap,
+        OmnixtendPermissionChangeGrow, OmnixtendSource, TilelinkMessage,
     },
-    utils::chunkize_packet,
+    utils::{chunkize_packet, Wakers},
 };
 
-#[derive(Debug, Snafu, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Snafu, PartialEq, Eq, Clone)]
 pub enum Error {
     #[snafu(display(
         "Operations can only performed on power of two data sizes: {} Bytes.",
@@ -39,8 +45,81 @@ pub enum Error {
     #[snafu(display("Access is not aligned with data size."))]
     UnalignedAccess {},
 
+    #[snafu(display(
+        "Message of {} bytes does not fit into a frame, the limit is {} bytes.",
+        size,
+        max
+    ))]
+    MessageTooLarge { size: usize, max: usize },
+
+    #[snafu(display("Virtual channel {} is not configured.", vc))]
+    InvalidVirtualChannel { vc: u8 },
+
     #[snafu(display("Did not receive response. Connection most likely closed."))]
     ConnectionClosed {},
+
+    #[snafu(display("Operation did not complete before its deadline."))]
+    Timeout {},
+
+    #[snafu(display("Operation was cancelled."))]
+    Cancelled {},
+
+    #[snafu(display("Connection to the peer was lost."))]
+    ConnectionLost {},
+
+    #[snafu(display(
+        "Access to 0x{:X} denied (Response opcode {}, Source {}).",
+        address,
+        opcode,
+        source
+    ))]
+    Denied {
+        opcode: u8,
+        address: u64,
+        #[snafu(source(false))]
+        source: u32,
+    },
+
+    #[snafu(display(
+        "Received corrupt data for 0x{:X} (Response opcode {}, Source {}).",
+        address,
+        opcode,
+        source
+    ))]
+    Corrupt {
+        opcode: u8,
+        address: u64,
+        #[snafu(source(false))]
+        source: u32,
+        data: Vec<u8>,
+    },
+
+    #[snafu(display(
+        "Received {} instead of {} bytes for 0x{:X} (Response opcode {}, Source {}).",
+        got,
+        expected,
+        address,
+        opcode,
+        source
+    ))]
+    SizeMismatch {
+        opcode: u8,
+        address: u64,
+        #[snafu(source(false))]
+        source: u32,
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl Error {
+    /// Returns the data of a corrupt response, for callers that can make use of it anyway.
+    pub fn corrupt_data(self) -> Option<Vec<u8>> {
+        match self {
+            Error::Corrupt { data, .. } => Some(data),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -74,6 +153,27 @@ pub struct WriteOpPartial<'a> {
     pub data: &'a [u8],
 }
 
+#[derive(Debug)]
+pub struct ArithmeticOp<'a> {
+    pub address: u64,
+    pub data: &'a [u8],
+    pub param: OmnixtendArithmetic,
+}
+
+#[derive(Debug)]
+pub struct LogicalOp<'a> {
+    pub address: u64,
+    pub data: &'a [u8],
+    pub param: OmnixtendLogical,
+}
+
+#[derive(Debug)]
+pub struct HintOp {
+    pub address: u64,
+    pub len: usize,
+    pub param: OmnixtendHint,
+}
+
 #[derive(Debug)]
 pub struct PermOp {
     pub address: u64,
@@ -108,6 +208,47 @@ pub struct ProbeDataOp<'a> {
     pub data: &'a [u8],
 }
 
+#[derive(Debug, Clone)]
+pub struct AccessAckOp {
+    pub source: OmnixtendSource,
+    pub size: u8,
+    pub denied: bool,
+}
+
+#[derive(Debug)]
+pub struct AccessAckDataOp<'a> {
+    pub ack: AccessAckOp,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub struct GrantOp {
+    pub source: OmnixtendSource,
+    pub sink: u32,
+    pub size: u8,
+    pub permissions: OmnixtendPermissionChangeCap,
+    pub denied: bool,
+}
+
+#[derive(Debug)]
+pub struct GrantDataOp<'a> {
+    pub grant: GrantOp,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub struct ReleaseAckOp {
+    pub source: OmnixtendSource,
+    pub size: u8,
+}
+
+#[derive(Debug)]
+pub struct ProbeRequestOp {
+    pub address: u64,
+    pub size: u8,
+    pub permissions: OmnixtendPermissionChangeCap,
+}
+
 #[derive(Debug)]
 pub enum TLOperations<'a> {
     Release(ReleaseOp),
@@ -122,22 +263,60 @@ pub enum TLOperations<'a> {
     ProbeAck(ProbeOp),
     ProbeAckData(ProbeDataOp<'a>),
     WritePartial(WriteOpPartial<'a>),
+    Arithmetic(ArithmeticOp<'a>),
+    Logical(LogicalOp<'a>),
+    Hint(HintOp),
+    AccessAck(AccessAckOp),
+    HintAck(AccessAckOp),
+    AccessAckData(AccessAckDataOp<'a>),
+    Grant(GrantOp),
+    GrantData(GrantDataOp<'a>),
+    ReleaseAck(ReleaseAckOp),
+    ProbeBlock(ProbeRequestOp),
+    ProbePerm(ProbeRequestOp),
 }
 
 impl TLOperations<'_> {
     fn has_return(&self) -> bool {
         !matches!(
             self,
-            TLOperations::GrantAck(_) | TLOperations::ProbeAck(_) | TLOperations::ProbeAckData(_)
+            TLOperations::GrantAck(_)
+                | TLOperations::ProbeAck(_)
+                | TLOperations::ProbeAckData(_)
+                | TLOperations::AccessAck(_)
+                | TLOperations::AccessAckData(_)
+                | TLOperations::HintAck(_)
+                | TLOperations::Grant(_)
+                | TLOperations::GrantData(_)
+                | TLOperations::ReleaseAck(_)
+                | TLOperations::ProbeBlock(_)
+                | TLOperations::ProbePerm(_)
         )
     }
 
-    fn get_response(&self, sink: u32) -> Option<Self> {
+    fn address(&self) -> u64 {
         match self {
-            TLOperations::AcquireBlock(_) | TLOperations::AcquirePerm(_) => {
-                Some(Self::GrantAck(sink))
-            }
-            _ => None,
+            TLOperations::Release(r) => r.address,
+            TLOperations::ReleaseData(r) => r.release.address,
+            TLOperations::AcquireBlock(r) | TLOperations::AcquirePerm(r) => r.address,
+            TLOperations::ReadLen(r) => r.address,
+            TLOperations::WriteLen(r) => r.address,
+            TLOperations::Read(r) => r.address,
+            TLOperations::Write(r) => r.address,
+            TLOperations::WritePartial(r) => r.address,
+            TLOperations::Arithmetic(r) => r.address,
+            TLOperations::Logical(r) => r.address,
+            TLOperations::Hint(r) => r.address,
+            TLOperations::ProbeAck(r) => r.address,
+            TLOperations::ProbeAckData(r) => r.probe.address,
+            TLOperations::ProbeBlock(r) | TLOperations::ProbePerm(r) => r.address,
+            TLOperations::GrantAck(_)
+            | TLOperations::AccessAck(_)
+            | TLOperations::AccessAckData(_)
+            | TLOperations::HintAck(_)
+            | TLOperations::Grant(_)
+            | TLOperations::GrantData(_)
+            | TLOperations::ReleaseAck(_) => 0,
         }
     }
 
@@ -173,20 +352,20 @@ impl TLOperations<'_> {
 
                 (OmnixtendChannel::A, 2 + mask_len + (next_pow2 / 8))
             }
+            TLOperations::Arithmetic(r) => (OmnixtendChannel::A, 2 + data_flits(r.data)),
+            TLOperations::Logical(r) => (OmnixtendChannel::A, 2 + data_flits(r.data)),
+            TLOperations::Hint(_) => (OmnixtendChannel::A, 2),
+            TLOperations::AccessAck(_) => (OmnixtendChannel::D, 1),
+            TLOperations::HintAck(_) => (OmnixtendChannel::D, 1),
+            TLOperations::AccessAckData(r) => (OmnixtendChannel::D, 1 + data_flits(r.data)),
+            TLOperations::Grant(_) => (OmnixtendChannel::D, 2),
+            TLOperations::GrantData(r) => (OmnixtendChannel::D, 2 + data_flits(r.data)),
+            TLOperations::ReleaseAck(_) => (OmnixtendChannel::D, 1),
+            TLOperations::ProbeBlock(_) => (OmnixtendChannel::B, 2),
+            TLOperations::ProbePerm(_) => (OmnixtendChannel::B, 2),
         }
     }
 
-    fn has_result_data(&self) -> bool {
-        matches!(
-            self,
-            TLOperations::ReadLen(_) | TLOperations::AcquireBlock(_)
-        )
-    }
-
-    fn has_result_data_64(&self) -> bool {
-        matches!(self, TLOperations::Read(_))
-    }
-
     fn pack_read_len(r: &ReadOpLen, source: u32) -> Result<Vec<u8>> {
         trace!("Adding read of {} bytes.", r.len_bytes);
         let len_log2 = (r.len_bytes as f64).log2();
@@ -251,6 +430,69 @@ impl TLOperations<'_> {
         Ok(buf)
     }
 
+    fn pack_atomic(
+        address: u64,
+        data: &[u8],
+        opcode: u8,
+        param: u8,
+        source: u32,
+    ) -> Result<Vec<u8>> {
+        let len_log2 = (data.len() as f64).log2();
+        if len_log2.fract() != 0.0 {
+            Err(Error::NotPowTwo { size: data.len() })?;
+        }
+        if address & (data.len() as u64 - 1) != 0 {
+            Err(Error::UnalignedAccess {})?;
+        }
+        let mut buf = vec![0; 16 + data_flits(data) * 8];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::A,
+                opcode,
+                param,
+                size: len_log2 as u8,
+                domain: 0,
+                err: 0,
+                source,
+            },
+        ))));
+        buf[8..16].copy_from_slice(&u64::to_be_bytes(address));
+        buf[16..16 + data.len()].copy_from_slice(data);
+        Ok(buf)
+    }
+
+    fn pack_arithmetic(r: &ArithmeticOp, source: u32) -> Result<Vec<u8>> {
+        trace!("Adding arithmetic {:?} of {} bytes.", r.param, r.data.len());
+        Self::pack_atomic(r.address, r.data, 2, r.param as u8, source)
+    }
+
+    fn pack_logical(r: &LogicalOp, source: u32) -> Result<Vec<u8>> {
+        trace!("Adding logical {:?} of {} bytes.", r.param, r.data.len());
+        Self::pack_atomic(r.address, r.data, 3, r.param as u8, source)
+    }
+
+    fn pack_hint(r: &HintOp, source: u32) -> Result<Vec<u8>> {
+        trace!("Adding hint {:?} of {} bytes.", r.param, r.len);
+        let len_log2 = (r.len as f64).log2();
+        if len_log2.fract() != 0.0 {
+            Err(Error::NotPowTwo { size: r.len })?;
+        }
+        let mut buf = vec![0; 16];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::A,
+                opcode: 5,
+                param: r.param as u8,
+                size: len_log2 as u8,
+                domain: 0,
+                err: 0,
+                source,
+            },
+        ))));
+        buf[8..16].copy_from_slice(&u64::to_be_bytes(r.address));
+        Ok(buf)
+    }
+
     fn pack_write_64(r: &WriteOp, source: u32) -> Result<Vec<u8>> {
         Self::pack_write_len(
             &WriteOpLen {
@@ -444,6 +686,145 @@ impl TLOperations<'_> {
 
         Ok(buf)
     }
+
+    fn pack_access_ack(r: &AccessAckOp, _source: u32) -> Result<Vec<u8>> {
+        trace!("Adding access ack for {}.", r.source);
+        let mut buf = vec![0; 8];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::D,
+                opcode: 0,
+                param: 0,
+                size: r.size,
+                domain: 0,
+                err: if r.denied { 0b10 } else { 0 },
+                source: r.source,
+            },
+        ))));
+        Ok(buf)
+    }
+
+    fn pack_hint_ack(r: &AccessAckOp, _source: u32) -> Result<Vec<u8>> {
+        trace!("Adding hint ack for {}.", r.source);
+        let mut buf = vec![0; 8];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::D,
+                opcode: 2,
+                param: 0,
+                size: r.size,
+                domain: 0,
+                err: if r.denied { 0b10 } else { 0 },
+                source: r.source,
+            },
+        ))));
+        Ok(buf)
+    }
+
+    fn pack_access_ack_data(r: &AccessAckDataOp, _source: u32) -> Result<Vec<u8>> {
+        trace!(
+            "Adding access ack data of {} bytes for {}.",
+            r.data.len(),
+            r.ack.source
+        );
+        let mut buf = vec![0; 8 + data_flits(r.data) * 8];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::D,
+                opcode: 1,
+                param: 0,
+                size: r.ack.size,
+                domain: 0,
+                // Denied data responses have to be flagged as corrupt as well
+                err: if r.ack.denied { 0b11 } else { 0 },
+                source: r.ack.source,
+            },
+        ))));
+        buf[8..8 + r.data.len()].copy_from_slice(r.data);
+        Ok(buf)
+    }
+
+    fn pack_grant(r: &GrantOp, _source: u32) -> Result<Vec<u8>> {
+        trace!("Adding grant for {} with sink {}.", r.source, r.sink);
+        let mut buf = vec![0; 16];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::D,
+                opcode: 4,
+                param: r.permissions as u8,
+                size: r.size,
+                domain: 0,
+                err: if r.denied { 0b10 } else { 0 },
+                source: r.source,
+            },
+        ))));
+        buf[8..16].copy_from_slice(&u64::to_be_bytes(r.sink as u64 & ((1 << 26) - 1)));
+        Ok(buf)
+    }
+
+    fn pack_grant_data(r: &GrantDataOp, _source: u32) -> Result<Vec<u8>> {
+        trace!(
+            "Adding grant data of {} bytes for {} with sink {}.",
+            r.data.len(),
+            r.grant.source,
+            r.grant.sink
+        );
+        let mut buf = vec![0; 16 + data_flits(r.data) * 8];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::D,
+                opcode: 5,
+                param: r.grant.permissions as u8,
+                size: r.grant.size,
+                domain: 0,
+                err: if r.grant.denied { 0b11 } else { 0 },
+                source: r.grant.source,
+            },
+        ))));
+        buf[8..16].copy_from_slice(&u64::to_be_bytes(r.grant.sink as u64 & ((1 << 26) - 1)));
+        buf[16..16 + r.data.len()].copy_from_slice(r.data);
+        Ok(buf)
+    }
+
+    fn pack_release_ack(r: &ReleaseAckOp, _source: u32) -> Result<Vec<u8>> {
+        trace!("Adding release ack for {}.", r.source);
+        let mut buf = vec![0; 8];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::D,
+                opcode: 6,
+                param: 0,
+                size: r.size,
+                domain: 0,
+                err: 0,
+                source: r.source,
+            },
+        ))));
+        Ok(buf)
+    }
+
+    fn pack_probe_request(r: &ProbeRequestOp, opcode: u8) -> Result<Vec<u8>> {
+        trace!("Adding probe to {:?} for 0x{:X}.", r.permissions, r.address);
+        let mut buf = vec![0; 16];
+        buf[0..8].copy_from_slice(&u64::to_be_bytes(u64::from(TilelinkMessage::ChanABCD(
+            ChanABCDTilelinkMessage {
+                chan: OmnixtendChannel::B,
+                opcode,
+                param: r.permissions as u8,
+                size: r.size,
+                domain: 0,
+                err: 0,
+                source: 0,
+            },
+        ))));
+        buf[8..16].copy_from_slice(&u64::to_be_bytes(r.address));
+        Ok(buf)
+    }
+}
+
+// Data in responses always occupies at least one flit, even for accesses smaller than 8 bytes.
+fn data_flits(data: &[u8]) -> usize {
+    max(data.len().div_ceil(8), 1)
 }
 
 struct OpAndSource<'a> {
@@ -466,6 +847,17 @@ impl<'a> From<&OpAndSource<'a>> for Result<Vec<u8>> {
             TLOperations::ProbeAck(r) => TLOperations::pack_probe(r, s.source),
             TLOperations::ProbeAckData(r) => TLOperations::pack_probe_data(r, s.source),
             TLOperations::WritePartial(r) => TLOperations::pack_write_partial(r, s.source),
+            TLOperations::Arithmetic(r) => TLOperations::pack_arithmetic(r, s.source),
+            TLOperations::Logical(r) => TLOperations::pack_logical(r, s.source),
+            TLOperations::Hint(r) => TLOperations::pack_hint(r, s.source),
+            TLOperations::AccessAck(r) => TLOperations::pack_access_ack(r, s.source),
+            TLOperations::HintAck(r) => TLOperations::pack_hint_ack(r, s.source),
+            TLOperations::AccessAckData(r) => TLOperations::pack_access_ack_data(r, s.source),
+            TLOperations::Grant(r) => TLOperations::pack_grant(r, s.source),
+            TLOperations::GrantData(r) => TLOperations::pack_grant_data(r, s.source),
+            TLOperations::ReleaseAck(r) => TLOperations::pack_release_ack(r, s.source),
+            TLOperations::ProbeBlock(r) => TLOperations::pack_probe_request(r, 6),
+            TLOperations::ProbePerm(r) => TLOperations::pack_probe_request(r, 7),
         }
     }
 }
@@ -493,12 +885,178 @@ impl TLResult {
     }
 }
 
+/// Shape of the result an operation produces once its response arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultKind {
+    None,
+    /// Data of the given length in bytes.
+    Data(usize),
+    Data64,
+}
+
+impl ResultKind {
+    /// Number of bytes the response has to carry. Checked before `extract` as the size of the
+    /// response is chosen by the peer.
+    fn expected_len(&self) -> Option<usize> {
+        match self {
+            ResultKind::Data(len) => Some(*len),
+            ResultKind::Data64 => Some(8),
+            ResultKind::None => None,
+        }
+    }
+
+    fn extract(&self, ret: Vec<u8>) -> TLResult {
+        match self {
+            ResultKind::Data(_) => TLResult::Data(ret),
+            ResultKind::Data64 => TLResult::Data64(u64::from_ne_bytes(
+                ret[..].try_into().expect("Not enough data."),
+            )),
+            ResultKind::None => TLResult::None,
+        }
+    }
+}
+
+impl From<&TLOperations<'_>> for ResultKind {
+    fn from(operation: &TLOperations) -> Self {
+        match operation {
+            TLOperations::ReadLen(r) => ResultKind::Data(r.len_bytes),
+            TLOperations::AcquireBlock(r) => ResultKind::Data(r.len),
+            TLOperations::Arithmetic(r) => ResultKind::Data(r.data.len()),
+            TLOperations::Logical(r) => ResultKind::Data(r.data.len()),
+            TLOperations::Read(_) => ResultKind::Data64,
+            _ => ResultKind::None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CompletionState {
+    result: Mutex<Option<Result<TLResult>>>,
+    done: Condvar,
+    waker: Mutex<Option<Waker>>,
+    finished: AtomicBool,
+}
+
+impl CompletionState {
+    /// Stores the result unless one has been stored before, e.g., a response arriving after the
+    /// operation timed out is dropped. Returns `false` in that case.
+    fn set(&self, r: Result<TLResult>) -> bool {
+        if self.finished.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        *self.result.lock() = Some(r);
+        self.done.notify_all();
+        if let Some(w) = self.waker.lock().take() {
+            w.wake();
+        }
+        true
+    }
+}
+
+struct PendingOperation {
+    state: Arc<CompletionState>,
+    kind: ResultKind,
+    address: u64,
+    grant_ack: bool,
+}
+
+/// Handle to an operation submitted through `Operations::submit`. The result is handed out once,
+/// either by `poll`, `wait_timeout`, `wait` or by awaiting the handle.
+pub struct Completion {
+    state: Arc<CompletionState>,
+}
+
+impl Completion {
+    fn done(r: Result<TLResult>) -> Self {
+        let state = Arc::new(CompletionState::default());
+        state.set(r);
+        Completion { state }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state.result.lock().is_some()
+    }
+
+    /// Returns the result if the response has arrived already.
+    pub fn poll(&self) -> Option<Result<TLResult>> {
+        self.state.result.lock().take()
+    }
+
+    /// Waits up to `timeout` for the response. Returns `None` if it did not arrive in time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<TLResult>> {
+        let mut result = self.state.result.lock();
+        if result.is_none() {
+            self.state.done.wait_for(&mut result, timeout);
+        }
+        result.take()
+    }
+
+    pub fn wait(self) -> Result<TLResult> {
+        let mut result = self.state.result.lock();
+        loop {
+            if let Some(r) = result.take() {
+                return r;
+            }
+            self.state.done.wait(&mut result);
+        }
+    }
+
+    /// Like `wait` but fails with `Error::Timeout` if the response did not arrive before
+    /// `deadline`. The response might still arrive later, it is dropped then.
+    pub fn wait_deadline(self, deadline: Instant) -> Result<TLResult> {
+        let mut result = self.state.result.lock();
+        loop {
+            if let Some(r) = result.take() {
+                return r;
+            }
+            if self
+                .state
+                .done
+                .wait_until(&mut result, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+        drop(result);
+        // The response might have arrived right after the deadline
+        if self.state.set(Err(Error::Timeout {})) {
+            debug!("Operation timed out.");
+        }
+        self.state
+            .result
+            .lock()
+            .take()
+            .unwrap_or(Err(Error::Timeout {}))
+    }
+}
+
+impl Future for Completion {
+    type Output = Result<TLResult>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut result = self.state.result.lock();
+        match result.take() {
+            Some(r) => Poll::Ready(r),
+            None => {
+                // Registered while holding the result lock so a concurrent `set` cannot be missed
+                *self.state.waker.lock() = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
 pub struct Operations {
     available_sources: SegQueue<OmnixtendSource>,
-    operation_completions_send: Vec<Sender<(u32, Result<Vec<u8>>)>>,
-    operation_completions_recv: Vec<Receiver<(u32, Result<Vec<u8>>)>>,
-    operations_outstanding: Mutex<Vec<Vec<u8>>>,
+    source_wakers: Wakers,
+    completions: Vec<Mutex<Option<PendingOperation>>>,
+    grant_acks: Mutex<VecDeque<u32>>,
+    operations_outstanding: Vec<Mutex<Vec<Vec<u8>>>>,
     outstanding_cntr: AtomicUsize,
+    timeout: Mutex<Option<Duration>>,
+    lost: AtomicBool,
+    max_message_size: usize,
 }
 
 impl Default for Operations {
@@ -507,145 +1065,547 @@ impl Default for Operations {
     }
 }
 
+impl Drop for Operations {
+    fn drop(&mut self) {
+        for c in self.completions.iter() {
+            if let Some(p) = c.lock().take() {
+                p.state.set(Err(Error::ConnectionClosed {}));
+            }
+        }
+    }
+}
+
 impl Operations {
     pub fn new() -> Self {
+        Self::with_config(&ConnectionConfig::default())
+    }
+
+    /// Creates operations with the source pool size, frame size and virtual channels of `config`.
+    pub fn with_config(config: &ConnectionConfig) -> Self {
         let available_sources = SegQueue::new();
-        let mut operation_completions_send = Vec::new();
-        let mut operation_completions_recv = Vec::new();
+        let mut completions = Vec::new();
 
-        (0..255).for_each(|i| {
+        (0..config.sources() as OmnixtendSource).for_each(|i| {
             available_sources.push(i);
-            let (s, r) = bounded(1);
-            operation_completions_send.push(s);
-            operation_completions_recv.push(r);
+            completions.push(Mutex::new(None));
         });
         Operations {
             available_sources,
-            operation_completions_send,
-            operation_completions_recv,
-            operations_outstanding: Mutex::new(Vec::new()),
+            source_wakers: Wakers::default(),
+            completions,
+            grant_acks: Mutex::new(VecDeque::new()),
+            operations_outstanding: (0..config.virtual_channels())
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
             outstanding_cntr: AtomicUsize::new(0),
+            timeout: Mutex::new(None),
+            lost: AtomicBool::new(false),
+            max_message_size: config.max_message_size(),
         }
     }
 
+    /// Default timeout of `perform` and `submit`, `None` waits forever.
+    pub fn timeout(&self) -> Option<Duration> {
+        *self.timeout.lock()
+    }
+
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        *self.timeout.lock() = timeout;
+    }
+
+    /// Performs `operation` and waits for its result. Fails with `Error::Timeout` if the default
+    /// timeout is set and expires while waiting for a source, credits or the response. A
+    /// `WriteLen` that does not fit into a single frame is split into several writes.
     pub fn perform(&self, operation: &TLOperations, credits: &Credits) -> Result<TLResult> {
-        self.outstanding_cntr.fetch_add(1, Ordering::Relaxed);
+        match self.timeout() {
+            Some(t) => self.perform_timeout(operation, credits, t),
+            None => {
+                let ret = self.perform_parts(operation, credits, None);
+                self.process_responses(credits);
+                ret
+            }
+        }
+    }
 
-        let source = self.get_source(operation);
+    /// Like `perform` but with a deadline `timeout` from now, regardless of the default timeout.
+    ///
+    /// The source of an operation that timed out is not reused until its response arrives, so a
+    /// late response cannot complete another operation. It is returned by `cancel_all` if the
+    /// response never arrives.
+    pub fn perform_timeout(
+        &self,
+        operation: &TLOperations,
+        credits: &Credits,
+        timeout: Duration,
+    ) -> Result<TLResult> {
+        let deadline = Instant::now() + timeout;
+        let ret = self.perform_parts(operation, credits, Some(deadline));
+        self.process_responses(credits);
+        ret
+    }
 
-        let op = self.create_operation(operation, source)?;
+    /// Submits the parts of `operation` before waiting for any of them. Returns the first error
+    /// or the result of the last part.
+    fn perform_parts(
+        &self,
+        operation: &TLOperations,
+        credits: &Credits,
+        deadline: Option<Instant>,
+    ) -> Result<TLResult> {
+        let parts = self.split_write(operation);
+        let completions = parts
+            .as_deref()
+            .unwrap_or(std::slice::from_ref(operation))
+            .iter()
+            .map(|o| match deadline {
+                Some(d) => self.submit_deadline(o, credits, d),
+                None => self.submit(o, credits),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut ret = Ok(TLResult::None);
+        for c in completions {
+            let r = match deadline {
+                Some(d) => c.wait_deadline(d),
+                None => c.wait(),
+            };
+            if ret.is_ok() {
+                ret = r;
+            }
+        }
+        ret
+    }
 
-        Self::get_credits(operation, credits);
+    /// Splits a `WriteLen` that does not fit into a single frame into writes of the largest power
+    /// of two that fits. Returns `None` for every other operation.
+    pub(crate) fn split_write<'a>(
+        &self,
+        operation: &TLOperations<'a>,
+    ) -> Option<Vec<TLOperations<'a>>> {
+        match operation {
+            TLOperations::WriteLen(r)
+                if r.data.len().is_power_of_two() && 16 + r.data.len() > self.max_message_size =>
+            {
+                let chunk = 1 << (self.max_message_size - 16).ilog2();
+                Some(
+                    r.data
+                        .chunks(chunk)
+                        .enumerate()
+                        .map(|(i, data)| {
+                            TLOperations::WriteLen(WriteOpLen {
+                                address: r.address + (i * chunk) as u64,
+                                data,
+                            })
+                        })
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
 
-        self.operations_outstanding.lock().push(op);
+    /// Queues `operation` for sending and returns a handle to its result. Waits until a source and
+    /// enough credits are available, but not for the response. Fails with `Error::Timeout` if the
+    /// default timeout expires first and with `Error::MessageTooLarge` if `operation` does not
+    /// fit into a single frame. The operation is sent on the virtual channel of `credits`, see
+    /// `Connection::vc_credits`.
+    pub fn submit(&self, operation: &TLOperations, credits: &Credits) -> Result<Completion> {
+        match self.timeout() {
+            Some(t) => self.submit_deadline(operation, credits, Instant::now() + t),
+            None => {
+                let source = self.get_source(operation, None)?;
+                self.enqueue(operation, source, credits, Some(None))
+                    .map(|c| c.expect("Blocking enqueue always succeeds."))
+            }
+        }
+    }
 
-        if operation.has_return() {
-            let (sink, ret) = self.wait_for_response(source);
+    /// Like `submit` but fails with `Error::Timeout` if no source or not enough credits become
+    /// available before `deadline`.
+    pub fn submit_deadline(
+        &self,
+        operation: &TLOperations,
+        credits: &Credits,
+        deadline: Instant,
+    ) -> Result<Completion> {
+        let source = self.get_source(operation, Some(deadline))?;
+        self.enqueue(operation, source, credits, Some(Some(deadline)))?
+            .ok_or(Error::Timeout {})
+    }
 
-            self.available_sources.push(source);
-            self.outstanding_cntr.fetch_sub(1, Ordering::Relaxed);
+    /// Like `submit` but returns `None` instead of waiting if no source or not enough credits are
+    /// available.
+    pub fn try_submit(
+        &self,
+        operation: &TLOperations,
+        credits: &Credits,
+    ) -> Result<Option<Completion>> {
+        let source = if operation.has_return() {
+            match self.available_sources.pop() {
+                Some(s) => s,
+                None => return Ok(None),
+            }
+        } else {
+            0
+        };
+
+        self.enqueue(operation, source, credits, None)
+    }
+
+    /// Queues `operation` with `source`. `wait` is `None` to give up immediately if not enough
+    /// credits are available, otherwise it holds the optional deadline for waiting for credits.
+    fn enqueue(
+        &self,
+        operation: &TLOperations,
+        source: u32,
+        credits: &Credits,
+        wait: Option<Option<Instant>>,
+    ) -> Result<Option<Completion>> {
+        self.outstanding_cntr.fetch_add(1, Ordering::Relaxed);
+
+        if self.is_lost() {
+            self.release_source(operation, source);
+            Err(Error::ConnectionLost {})?;
+        }
+
+        let queue = match self.operations_outstanding.get(credits.vc() as usize) {
+            Some(q) => q,
+            None => {
+                self.release_source(operation, source);
+                Err(Error::InvalidVirtualChannel { vc: credits.vc() })?
+            }
+        };
+
+        let op = self.create_operation(operation, source)?;
 
-            if ret != Err(Error::ConnectionClosed {}) {
-                self.send_response(operation, sink, credits);
+        match wait {
+            Some(deadline) => {
+                if let Err(e) = self.get_credits(operation, credits, deadline) {
+                    self.release_source(operation, source);
+                    Err(e)?;
+                }
+            }
+            None => {
+                if !Self::try_get_credits(operation, credits) {
+                    credits.stalled(operation.credits().0);
+                    self.release_source(operation, source);
+                    return Ok(None);
+                }
             }
+        }
 
-            self.extract_response(ret, operation)
+        let completion = if operation.has_return() {
+            let state = Arc::new(CompletionState::default());
+            *self.completions[source as usize].lock() = Some(PendingOperation {
+                state: state.clone(),
+                kind: ResultKind::from(operation),
+                address: operation.address(),
+                grant_ack: matches!(
+                    operation,
+                    TLOperations::AcquireBlock(_) | TLOperations::AcquirePerm(_)
+                ),
+            });
+            // The connection might have been lost while waiting for credits
+            if self.is_lost() {
+                if let Some(p) = self.completions[source as usize].lock().take() {
+                    p.state.set(Err(Error::ConnectionLost {}));
+                    self.release_source(operation, source);
+                }
+                return Ok(Some(Completion { state }));
+            }
+            Completion { state }
         } else {
             self.outstanding_cntr.fetch_sub(1, Ordering::Relaxed);
-            Ok(TLResult::None)
-        }
+            Completion::done(Ok(TLResult::None))
+        };
+
+        queue.lock().push(op);
+
+        Ok(Some(completion))
     }
 
-    fn wait_for_response(&self, source: u32) -> (u32, Result<Vec<u8>, Error>) {
-        self.operation_completions_recv[source as usize]
-            .recv()
-            .unwrap_or_else(|_v| {
-                trace!("Sender has been closed. Program is most likely terminating. Returning dummy data.");
-                (0, Err(Error::ConnectionClosed{} ))
-            })
+    /// Uncached atomic on the 64 bit value at `address`. Returns the value before the operation.
+    pub fn arithmetic(
+        &self,
+        credits: &Credits,
+        address: u64,
+        value: u64,
+        param: OmnixtendArithmetic,
+    ) -> Result<u64> {
+        let data = value.to_ne_bytes();
+        let ret = self.perform(
+            &TLOperations::Arithmetic(ArithmeticOp {
+                address,
+                data: &data,
+                param,
+            }),
+            credits,
+        )?;
+        Ok(u64::from_ne_bytes(
+            ret.get_data()[..].try_into().expect("Not enough data."),
+        ))
     }
 
-    fn extract_response(
+    /// Uncached logical operation on the 64 bit value at `address`. Returns the value before the
+    /// operation.
+    pub fn logical(
         &self,
-        ret: Result<Vec<u8>, Error>,
-        operation: &TLOperations,
-    ) -> Result<TLResult> {
-        match ret {
-            Ok(r) => Ok(self.complete_inner(operation, r)),
-            Err(e) => Err(e),
-        }
+        credits: &Credits,
+        address: u64,
+        value: u64,
+        param: OmnixtendLogical,
+    ) -> Result<u64> {
+        let data = value.to_ne_bytes();
+        let ret = self.perform(
+            &TLOperations::Logical(LogicalOp {
+                address,
+                data: &data,
+                param,
+            }),
+            credits,
+        )?;
+        Ok(u64::from_ne_bytes(
+            ret.get_data()[..].try_into().expect("Not enough data."),
+        ))
+    }
+
+    pub fn fetch_add(&self, credits: &Credits, address: u64, value: u64) -> Result<u64> {
+        self.arithmetic(credits, address, value, OmnixtendArithmetic::Add)
+    }
+
+    pub fn swap(&self, credits: &Credits, address: u64, value: u64) -> Result<u64> {
+        self.logical(credits, address, value, OmnixtendLogical::Swap)
+    }
+
+    /// Sends a prefetch hint for `len` bytes at `address` without waiting for the HintAck. The
+    /// returned handle completes once the endpoint has acknowledged the hint.
+    pub fn hint(
+        &self,
+        credits: &Credits,
+        address: u64,
+        len: usize,
+        param: OmnixtendHint,
+    ) -> Result<Completion> {
+        self.submit(
+            &TLOperations::Hint(HintOp {
+                address,
+                len,
+                param,
+            }),
+            credits,
+        )
     }
 
-    fn send_response(&self, operation: &TLOperations, sink: u32, credits: &Credits) {
-        if let Some(op) = operation.get_response(sink) {
-            if let Err(e) = self.perform(&op, credits) {
-                error!("Failed to send response: {:?}", e);
+    /// Sends the GrantAcks for completed acquires on the virtual channel of `credits`. Called
+    /// periodically as they might have to wait for credits.
+    pub fn process_responses(&self, credits: &Credits) {
+        let queue = match self.operations_outstanding.get(credits.vc() as usize) {
+            Some(q) => q,
+            None => return,
+        };
+        let mut grant_acks = self.grant_acks.lock();
+        while let Some(sink) = grant_acks.front() {
+            let op = TLOperations::GrantAck(*sink);
+            if !Self::try_get_credits(&op, credits) {
+                break;
             }
+            match Result::from(&OpAndSource {
+                operation: &op,
+                source: 0,
+            }) {
+                Ok(p) => queue.lock().push(p),
+                Err(e) => error!("Failed to send response: {:?}", e),
+            }
+            grant_acks.pop_front();
+        }
+    }
+
+    fn release_source(&self, operation: &TLOperations, source: u32) {
+        if operation.has_return() {
+            self.available_sources.push(source);
         }
+        self.outstanding_cntr.fetch_sub(1, Ordering::Relaxed);
     }
 
     fn create_operation(&self, operation: &TLOperations, source: u32) -> Result<Vec<u8>> {
-        let op = Result::from(&OpAndSource { operation, source }).map_err(|e| {
-            if operation.has_return() {
-                self.available_sources.push(source);
-            }
-            self.outstanding_cntr.fetch_sub(1, Ordering::Relaxed);
-            e
-        })?;
+        let op = Result::from(&OpAndSource { operation, source })
+            .and_then(|op| {
+                // The message would never be sent, blocking all messages queued after it
+                if op.len() > self.max_message_size {
+                    Err(Error::MessageTooLarge {
+                        size: op.len(),
+                        max: self.max_message_size,
+                    })?;
+                }
+                Ok(op)
+            })
+            .map_err(|e| {
+                self.release_source(operation, source);
+                e
+            })?;
         Ok(op)
     }
 
-    fn get_source(&self, operation: &TLOperations) -> u32 {
+    fn get_source(&self, operation: &TLOperations, deadline: Option<Instant>) -> Result<u32> {
         let backoff = Backoff::new();
         if operation.has_return() {
             loop {
                 match self.available_sources.pop() {
-                    Some(a) => break a,
+                    Some(a) => break Ok(a),
+                    None if self.is_lost() => break Err(Error::ConnectionLost {}),
+                    None if deadline.is_some_and(|d| Instant::now() >= d) => {
+                        break Err(Error::Timeout {})
+                    }
                     None => backoff.snooze(),
                 }
             }
         } else {
-            0
+            Ok(0)
         }
     }
 
-    fn complete_inner(&self, operation: &TLOperations, ret: Vec<u8>) -> TLResult {
-        if operation.has_result_data() {
-            TLResult::Data(ret)
-        } else if operation.has_result_data_64() {
-            TLResult::Data64(u64::from_ne_bytes(
-                ret[..].try_into().expect("Not enough data."),
-            ))
-        } else {
-            TLResult::None
+    /// Fails all operations waiting for a response with `Error::Cancelled`, drops all packets
+    /// that have not been sent yet and makes all sources available again, including those of
+    /// operations that timed out. Only call this once no more responses can arrive, e.g., after
+    /// the connection has been closed, as a late response would complete a reused source.
+    pub fn cancel_all(&self) {
+        self.abort_all(Error::Cancelled {});
+    }
+
+    /// Fails all outstanding operations with `Error::ConnectionLost`, as well as all operations
+    /// waiting for a source or credits and all operations submitted afterwards. Called by `Tick`
+    /// once the connection has failed.
+    pub fn connection_lost(&self) {
+        self.lost.store(true, Ordering::Release);
+        self.abort_all(Error::ConnectionLost {});
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Acquire)
+    }
+
+    fn abort_all(&self, error: Error) {
+        self.operations_outstanding
+            .iter()
+            .for_each(|q| q.lock().clear());
+        self.grant_acks.lock().clear();
+        let mut aborted = 0;
+        for (source, c) in self.completions.iter().enumerate() {
+            if let Some(p) = c.lock().take() {
+                if p.state.set(Err(error.clone())) {
+                    aborted += 1;
+                }
+                self.available_sources.push(source as OmnixtendSource);
+                self.outstanding_cntr.fetch_sub(1, Ordering::Relaxed);
+            }
         }
+        self.source_wakers.wake();
+        debug!("Aborted {} outstanding operations: {}", aborted, error);
     }
 
-    pub fn complete(&self, source: u32, sink: u32, r: Result<Vec<u8>>) {
-        if self.operation_completions_send.is_empty() {
-            trace!("Cannot complete transaction on closed connection.");
-            return;
+    pub fn complete(&self, response: Response) {
+        let source = response.source;
+        let pending = match self.completions.get(source as usize) {
+            Some(c) => c.lock().take(),
+            None => None,
+        };
+
+        let pending = match pending {
+            Some(p) => p,
+            None => {
+                trace!("No operation outstanding for source {}.", source);
+                return;
+            }
+        };
+
+        trace!("Completing source {} with {:?}", source, response);
+        // The operation might have timed out already, the source is safe to reuse either way
+        self.available_sources.push(source);
+        self.outstanding_cntr.fetch_sub(1, Ordering::Relaxed);
+        self.source_wakers.wake();
+
+        // Denied grants have to be acknowledged as well
+        if pending.grant_ack {
+            self.grant_acks.lock().push_back(response.sink);
+        }
+
+        let r = if response.denied {
+            Err(Error::Denied {
+                opcode: response.opcode,
+                address: pending.address,
+                source,
+            })
+        } else if response.corrupt {
+            Err(Error::Corrupt {
+                opcode: response.opcode,
+                address: pending.address,
+                source,
+                data: response.data,
+            })
+        } else if let Some(expected) = pending
+            .kind
+            .expected_len()
+            .filter(|l| *l != response.data.len())
+        {
+            Err(Error::SizeMismatch {
+                opcode: response.opcode,
+                address: pending.address,
+                source,
+                expected,
+                got: response.data.len(),
+            })
+        } else {
+            Ok(pending.kind.extract(response.data))
+        };
+        if !pending.state.set(r) {
+            debug!("Dropped late response for source {}.", source);
         }
+    }
 
-        trace!("Completing source {} sink {} result {:?}", source, sink, r);
-        self.operation_completions_send[source as usize]
-            .send((sink, r))
-            .unwrap();
+    /// Wakes the task of future `id` once a source becomes available again.
+    pub(crate) fn register_source_waker(&self, id: usize, waker: &Waker) {
+        self.source_wakers.register(id, waker);
+    }
+
+    pub(crate) fn remove_source_waker(&self, id: usize) {
+        self.source_wakers.remove(id);
     }
 
     pub fn num_outstanding(&self) -> usize {
         self.outstanding_cntr.load(Ordering::Relaxed)
     }
 
-    pub fn operations_outstanding(&self) -> &Mutex<Vec<Vec<u8>>> {
-        &self.operations_outstanding
+    /// Messages waiting to be sent on virtual channel `vc`.
+    pub fn operations_outstanding(&self, vc: u8) -> Option<&Mutex<Vec<Vec<u8>>>> {
+        self.operations_outstanding.get(vc as usize)
     }
 
-    fn get_credits(operation: &TLOperations, credits: &Credits) {
+    fn get_credits(
+        &self,
+        operation: &TLOperations,
+        credits: &Credits,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        if Self::try_get_credits(operation, credits) {
+            return Ok(());
+        }
+        credits.stalled(operation.credits().0);
+
         let backoff = Backoff::new();
-        let (chan, credit) = operation.credits();
-        while !credits.take(chan, credit) {
+        while !Self::try_get_credits(operation, credits) {
+            if self.is_lost() {
+                Err(Error::ConnectionLost {})?;
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                Err(Error::Timeout {})?;
+            }
             backoff.snooze()
         }
+        Ok(())
+    }
+
+    fn try_get_credits(operation: &TLOperations, credits: &Credits) -> bool {
+        let (chan, credit) = operation.credits();
+        credits.take(chan, credit)
     }
 }
@@ -0,0 +1,596 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use snafu::ResultExt;
+
+use crate::{
+    config::MAX_VIRTUAL_CHANNELS,
+    connection::Connection,
+    credits::Credits,
+    directory::{Directory, DENIED_SINK},
+    operations::{
+        AccessAckDataOp, AccessAckOp, GrantDataOp, GrantOp, Operations, ProbeRequestOp,
+        ReleaseAckOp, TLOperations,
+    },
+    tilelink_messages::{
+        get_resulting_permission, ChanABCDTilelinkMessage, OmnixtendChannel,
+        OmnixtendPermissionChangeGrow,
+    },
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to execute operation: {}", source))]
+    Operations { source: crate::operations::Error },
+
+    #[snafu(display("Failed to select virtual channel: {}", source))]
+    VirtualChannel { source: crate::connection::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A TileLink message received by the responder on channel A, C or E.
+#[derive(Debug, Clone)]
+pub enum Request {
+    Message {
+        msg: ChanABCDTilelinkMessage,
+        address: u64,
+        data: Vec<u8>,
+        mask: Option<Vec<bool>>,
+    },
+    GrantAck {
+        sink: u32,
+    },
+}
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Sparse byte addressable memory backing the software endpoint. Unwritten memory reads as zero.
+pub struct Memory {
+    pages: DashMap<u64, Vec<u8>>,
+    size: u64,
+}
+
+impl Memory {
+    pub fn new(size: u64) -> Self {
+        Memory {
+            pages: DashMap::new(),
+            size,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn contains(&self, address: u64, len: usize) -> bool {
+        address
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= self.size)
+    }
+
+    pub fn read(&self, address: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        for (i, d) in data.iter_mut().enumerate() {
+            let addr = address + i as u64;
+            if let Some(page) = self.pages.get(&(addr / PAGE_SIZE)) {
+                *d = page[(addr % PAGE_SIZE) as usize];
+            }
+        }
+        data
+    }
+
+    pub fn write(&self, address: u64, data: &[u8]) {
+        for (i, d) in data.iter().enumerate() {
+            self.write_byte(address + i as u64, *d);
+        }
+    }
+
+    pub fn write_masked(&self, address: u64, data: &[u8], mask: &[bool]) {
+        for (i, (d, m)) in data.iter().zip(mask.iter()).enumerate() {
+            if *m {
+                self.write_byte(address + i as u64, *d);
+            }
+        }
+    }
+
+    fn write_byte(&self, addr: u64, d: u8) {
+        self.pages
+            .entry(addr / PAGE_SIZE)
+            .or_insert_with(|| vec![0; PAGE_SIZE as usize])[(addr % PAGE_SIZE) as usize] = d;
+    }
+}
+
+/// Response of the responder. Kept until enough credits are available to send it, so a request
+/// is only executed once.
+#[derive(Debug)]
+enum Reply {
+    AccessAck(AccessAckOp),
+    AccessAckData(AccessAckOp, Vec<u8>),
+    HintAck(AccessAckOp),
+    Grant(GrantOp),
+    GrantData(GrantOp, Vec<u8>),
+    ReleaseAck(ReleaseAckOp),
+}
+
+impl Reply {
+    fn operation(&self) -> TLOperations<'_> {
+        match self {
+            Reply::AccessAck(ack) => TLOperations::AccessAck(ack.clone()),
+            Reply::AccessAckData(ack, data) => TLOperations::AccessAckData(AccessAckDataOp {
+                ack: ack.clone(),
+                data,
+            }),
+            Reply::HintAck(ack) => TLOperations::HintAck(ack.clone()),
+            Reply::Grant(grant) => TLOperations::Grant(grant.clone()),
+            Reply::GrantData(grant, data) => TLOperations::GrantData(GrantDataOp {
+                grant: grant.clone(),
+                data,
+            }),
+            Reply::ReleaseAck(ack) => TLOperations::ReleaseAck(ack.clone()),
+        }
+    }
+}
+
+/// Responder side of a single OmniXtend connection. Requests are collected while parsing packets
+/// and answered from the attached memory during `process_requests`. Requests conflicting with
+/// permissions held by other clients of the shared directory are kept until the probes are done.
+/// Requests are answered on the virtual channel they were received on, probes are sent on VC 0.
+/// Sending never waits for credits, so the packet processing can always return them.
+pub struct Responder {
+    id: u8,
+    memory: Arc<Memory>,
+    directory: Arc<Directory>,
+    requests: Mutex<Vec<(u8, Request)>>,
+    replies: Mutex<Vec<(u8, Reply)>>,
+}
+
+impl Responder {
+    pub fn new(id: u8, memory: Arc<Memory>, directory: Arc<Directory>) -> Self {
+        Responder {
+            id,
+            memory,
+            directory,
+            requests: Mutex::new(Vec::new()),
+            replies: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn memory(&self) -> &Arc<Memory> {
+        &self.memory
+    }
+
+    pub fn directory(&self) -> &Arc<Directory> {
+        &self.directory
+    }
+
+    /// Adds a request received on virtual channel `vc`.
+    pub fn add_request(&self, vc: u8, request: Request) {
+        self.requests.lock().push((vc, request))
+    }
+
+    pub fn process_requests(&self, operations: &Operations, connection: &Connection) {
+        self.process_probes(operations, connection.credits());
+
+        // The lock is not held while handling, new requests are added by the receiving thread
+        let requests = std::mem::take(&mut *self.requests.lock());
+        let mut blocked: Vec<(u8, Request)> = requests
+            .into_iter()
+            .filter(|(vc, r)| !self.handle_request(*vc, r))
+            .collect();
+        if !blocked.is_empty() {
+            let mut requests = self.requests.lock();
+            blocked.append(&mut requests);
+            *requests = blocked;
+        }
+
+        self.send_replies(operations, connection);
+    }
+
+    /// Sends the replies in order. Once a reply has to wait for credits, the following replies
+    /// on its virtual channel wait as well. Replies failing for other reasons, e.g., a lost
+    /// connection or a response larger than a frame, are dropped.
+    fn send_replies(&self, operations: &Operations, connection: &Connection) {
+        let mut stalled = [false; MAX_VIRTUAL_CHANNELS];
+        self.replies.lock().retain(|(vc, reply)| {
+            if stalled[*vc as usize] {
+                return true;
+            }
+            let sent = connection
+                .vc_credits(*vc)
+                .context(VirtualChannelSnafu)
+                .and_then(|credits| {
+                    operations
+                        .try_submit(&reply.operation(), credits)
+                        .context(OperationsSnafu)
+                });
+            match sent {
+                Ok(Some(_)) => false,
+                Ok(None) => {
+                    stalled[*vc as usize] = true;
+                    true
+                }
+                Err(e) => {
+                    error!("Sim {}: Dropping reply {:?}: {}", self.id, reply, e);
+                    false
+                }
+            }
+        });
+    }
+
+    fn reply(&self, vc: u8, reply: Reply) {
+        self.replies.lock().push((vc, reply));
+    }
+
+    fn process_probes(&self, operations: &Operations, credits: &Credits) {
+        let mut probes = self.directory.take_probes(self.id);
+        probes.retain(|p| {
+            trace!(
+                "Sim {}: RESPONDER Probing 0x{:X} to {:?}.",
+                self.id,
+                p.address,
+                p.permissions
+            );
+            let op = ProbeRequestOp {
+                address: p.address,
+                size: p.size,
+                permissions: p.permissions,
+            };
+            let op = if p.block {
+                TLOperations::ProbeBlock(op)
+            } else {
+                TLOperations::ProbePerm(op)
+            };
+            match operations.try_submit(&op, credits) {
+                Ok(Some(_)) => false,
+                Ok(None) => true,
+                Err(e) => {
+                    error!("Sim {}: Dropping probe {:?}: {}", self.id, p, e);
+                    false
+                }
+            }
+        });
+        for p in probes {
+            self.directory.retry_probe(self.id, p);
+        }
+    }
+
+    /// Returns false if the request has to wait for the directory.
+    fn handle_request(&self, vc: u8, request: &Request) -> bool {
+        match request {
+            Request::Message {
+                msg,
+                address,
+                data,
+                mask,
+            } => match (msg.chan, msg.opcode) {
+                (OmnixtendChannel::A, 0) | (OmnixtendChannel::A, 1) => {
+                    self.handle_put(vc, msg, *address, data, mask)
+                }
+                (OmnixtendChannel::A, 2) | (OmnixtendChannel::A, 3) => {
+                    self.handle_atomic(vc, msg, *address, data)
+                }
+                (OmnixtendChannel::A, 4) => self.handle_get(vc, msg, *address),
+                (OmnixtendChannel::A, 5) => self.handle_hint(vc, msg, *address),
+                (OmnixtendChannel::A, 6) | (OmnixtendChannel::A, 7) => {
+                    self.handle_acquire(vc, msg, *address)
+                }
+                (OmnixtendChannel::C, 4) | (OmnixtendChannel::C, 5) => {
+                    trace!(
+                        "Sim {}: RESPONDER Received probe ack for 0x{:X}.",
+                        self.id,
+                        address
+                    );
+                    if !data.is_empty() && self.memory.contains(*address, data.len()) {
+                        self.memory.write(*address, data);
+                    }
+                    self.directory.probe_ack(self.id, *address, msg.param);
+                    true
+                }
+                (OmnixtendChannel::C, 6) | (OmnixtendChannel::C, 7) => {
+                    self.handle_release(vc, msg, *address, data)
+                }
+                _ => {
+                    error!(
+                        "Sim {}: RESPONDER Unhandled request {:?}. Dropping.",
+                        self.id, msg
+                    );
+                    true
+                }
+            },
+            Request::GrantAck { sink } => {
+                trace!(
+                    "Sim {}: RESPONDER Received grant ack for {}.",
+                    self.id,
+                    sink
+                );
+                self.directory.grant_ack(*sink);
+                true
+            }
+        }
+    }
+
+    fn handle_put(
+        &self,
+        vc: u8,
+        msg: &ChanABCDTilelinkMessage,
+        address: u64,
+        data: &[u8],
+        mask: &Option<Vec<bool>>,
+    ) -> bool {
+        // Requests outside of the memory never reach the directory
+        let denied = !self.memory.contains(address, data.len());
+        if !denied
+            && !self
+                .directory
+                .access(self.id, msg.source, address, data.len() as u64, true)
+        {
+            return false;
+        }
+
+        if !denied {
+            trace!(
+                "Sim {}: RESPONDER Writing {} bytes to 0x{:X}.",
+                self.id,
+                data.len(),
+                address
+            );
+            match mask {
+                Some(m) => self.memory.write_masked(address, data, m),
+                None => self.memory.write(address, data),
+            }
+        }
+        self.reply(
+            vc,
+            Reply::AccessAck(AccessAckOp {
+                source: msg.source,
+                size: msg.size,
+                denied,
+            }),
+        );
+        true
+    }
+
+    fn handle_get(&self, vc: u8, msg: &ChanABCDTilelinkMessage, address: u64) -> bool {
+        let len = 1 << msg.size;
+        let denied = !self.memory.contains(address, len);
+        if !denied
+            && !self
+                .directory
+                .access(self.id, msg.source, address, len as u64, false)
+        {
+            return false;
+        }
+
+        let data = if denied {
+            vec![0; len]
+        } else {
+            self.memory.read(address, len)
+        };
+        trace!(
+            "Sim {}: RESPONDER Reading {} bytes from 0x{:X}.",
+            self.id,
+            len,
+            address
+        );
+        self.reply(
+            vc,
+            Reply::AccessAckData(
+                AccessAckOp {
+                    source: msg.source,
+                    size: msg.size,
+                    denied,
+                },
+                data,
+            ),
+        );
+        true
+    }
+
+    fn handle_atomic(
+        &self,
+        vc: u8,
+        msg: &ChanABCDTilelinkMessage,
+        address: u64,
+        data: &[u8],
+    ) -> bool {
+        let mut denied = !self.memory.contains(address, data.len());
+        if !denied
+            && !self
+                .directory
+                .access(self.id, msg.source, address, data.len() as u64, true)
+        {
+            return false;
+        }
+
+        let old = if denied {
+            vec![0; data.len()]
+        } else {
+            self.memory.read(address, data.len())
+        };
+        if !denied {
+            match atomic_result(msg.opcode, msg.param, &old, data) {
+                Some(new) => {
+                    trace!(
+                        "Sim {}: RESPONDER Atomic {}/{} on {} bytes at 0x{:X}.",
+                        self.id,
+                        msg.opcode,
+                        msg.param,
+                        data.len(),
+                        address
+                    );
+                    self.memory.write(address, &new);
+                }
+                None => {
+                    error!(
+                        "Sim {}: RESPONDER Invalid atomic param {} for opcode {}.",
+                        self.id, msg.param, msg.opcode
+                    );
+                    denied = true;
+                }
+            }
+        }
+        self.reply(
+            vc,
+            Reply::AccessAckData(
+                AccessAckOp {
+                    source: msg.source,
+                    size: msg.size,
+                    denied,
+                },
+                old,
+            ),
+        );
+        true
+    }
+
+    fn handle_hint(&self, vc: u8, msg: &ChanABCDTilelinkMessage, address: u64) -> bool {
+        // Memory is not cached by the endpoint, so hints only have to be acknowledged
+        trace!(
+            "Sim {}: RESPONDER Hint {} for 0x{:X}.",
+            self.id,
+            msg.param,
+            address
+        );
+        self.reply(
+            vc,
+            Reply::HintAck(AccessAckOp {
+                source: msg.source,
+                size: msg.size,
+                denied: !self.memory.contains(address, 1 << msg.size),
+            }),
+        );
+        true
+    }
+
+    fn handle_acquire(&self, vc: u8, msg: &ChanABCDTilelinkMessage, address: u64) -> bool {
+        let len = 1 << msg.size;
+        let grow = OmnixtendPermissionChangeGrow::from(msg.param);
+        let denied = !self.memory.contains(address, len);
+        // Nobody can own a block outside of the memory, so the directory does not track it
+        let (permissions, sink) = if denied {
+            (get_resulting_permission(&grow), DENIED_SINK)
+        } else {
+            match self.directory.acquire(
+                self.id,
+                msg.source,
+                address,
+                msg.size,
+                grow,
+                msg.opcode == 6,
+            ) {
+                Some(x) => x,
+                None => return false,
+            }
+        };
+        let grant = GrantOp {
+            source: msg.source,
+            sink,
+            size: msg.size,
+            permissions,
+            denied,
+        };
+        trace!(
+            "Sim {}: RESPONDER Granting {:?} for 0x{:X}.",
+            self.id,
+            grant.permissions,
+            address
+        );
+        if msg.opcode == 6 {
+            let data = if denied {
+                vec![0; len]
+            } else {
+                self.memory.read(address, len)
+            };
+            self.reply(vc, Reply::GrantData(grant, data));
+        } else {
+            self.reply(vc, Reply::Grant(grant));
+        }
+        true
+    }
+
+    fn handle_release(
+        &self,
+        vc: u8,
+        msg: &ChanABCDTilelinkMessage,
+        address: u64,
+        data: &[u8],
+    ) -> bool {
+        if !data.is_empty() && self.memory.contains(address, data.len()) {
+            trace!(
+                "Sim {}: RESPONDER Writing back {} bytes to 0x{:X}.",
+                self.id,
+                data.len(),
+                address
+            );
+            self.memory.write(address, data);
+        }
+        self.directory.release(self.id, address, msg.param);
+        self.reply(
+            vc,
+            Reply::ReleaseAck(ReleaseAckOp {
+                source: msg.source,
+                size: msg.size,
+            }),
+        );
+        true
+    }
+}
+
+/// Applies an ArithmeticData (opcode 2) or LogicalData (opcode 3) operation to `old`. Data wider
+/// than 8 bytes is handled as independent 64 bit lanes, like the hardware endpoint does. Values are
+/// little endian. Returns `None` for invalid params.
+fn atomic_result(opcode: u8, param: u8, old: &[u8], operand: &[u8]) -> Option<Vec<u8>> {
+    let mut new = Vec::with_capacity(old.len());
+    for (o, n) in old.chunks(8).zip(operand.chunks(8)) {
+        let bits = o.len() as u32 * 8;
+        let mut buf = [0; 8];
+        buf[..o.len()].copy_from_slice(o);
+        let ou = u64::from_le_bytes(buf);
+        buf[..n.len()].copy_from_slice(n);
+        let nu = u64::from_le_bytes(buf);
+        // Sign extend for the signed comparisons
+        let os = ((ou << (64 - bits)) as i64) >> (64 - bits);
+        let ns = ((nu << (64 - bits)) as i64) >> (64 - bits);
+
+        let r = match (opcode, param) {
+            (2, 0) => {
+                if ns < os {
+                    nu
+                } else {
+                    ou
+                }
+            }
+            (2, 1) => {
+                if ns > os {
+                    nu
+                } else {
+                    ou
+                }
+            }
+            (2, 2) => ou.min(nu),
+            (2, 3) => ou.max(nu),
+            (2, 4) => ou.wrapping_add(nu),
+            (3, 0) => ou ^ nu,
+            (3, 1) => ou | nu,
+            (3, 2) => ou & nu,
+            (3, 3) => nu,
+            _ => return None,
+        };
+        new.extend_from_slice(&r.to_le_bytes()[..o.len()]);
+    }
+    Some(new)
+}
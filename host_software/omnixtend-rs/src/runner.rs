@@ -0,0 +1,213 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    cache::Cache,
+    connection::{Connection, ConnectionState},
+    endpoint::Endpoint,
+    operations::Operations,
+    tick::Tick,
+    transport::Transport,
+    utils::process_packet,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Runner thread {} panicked.", name))]
+    ThreadPanicked { name: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Protocol side driven by a `Runner`.
+pub trait Node: Send + Sync {
+    /// Handles a frame received from the transport.
+    fn process_frame(&self, frame: &[u8]);
+
+    /// Returns the next frame to send, if any.
+    fn next_frame(&self) -> Option<Vec<u8>>;
+
+    /// Called periodically to advance timers, send acks and resends.
+    fn tick(&self);
+
+    /// The runner stops once this returns true.
+    fn finished(&self) -> bool {
+        false
+    }
+}
+
+/// Requester side of a single connection, i.e., the connection together with its cache and
+/// operations. Finished once the connection has become idle or failed, so the connection should
+/// be established before the requester is handed to a runner.
+pub struct Requester {
+    connection: Arc<Connection>,
+    cache: Arc<Cache>,
+    operations: Arc<Operations>,
+    tick: Mutex<Tick>,
+}
+
+impl Requester {
+    pub fn new(
+        connection: Arc<Connection>,
+        cache: Arc<Cache>,
+        operations: Arc<Operations>,
+        tick: Tick,
+    ) -> Self {
+        Requester {
+            connection,
+            cache,
+            operations,
+            tick: Mutex::new(tick),
+        }
+    }
+
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+
+    pub fn cache(&self) -> &Arc<Cache> {
+        &self.cache
+    }
+
+    pub fn operations(&self) -> &Arc<Operations> {
+        &self.operations
+    }
+}
+
+impl Node for Requester {
+    fn process_frame(&self, frame: &[u8]) {
+        if let Err(e) = process_packet(frame, &self.connection, &self.cache, &self.operations) {
+            trace!("Failed parsing packet: {}", e);
+        }
+    }
+
+    fn next_frame(&self) -> Option<Vec<u8>> {
+        self.connection.get_packet()
+    }
+
+    fn tick(&self) {
+        self.tick
+            .lock()
+            .tick(&self.operations, &self.connection, &self.cache);
+    }
+
+    fn finished(&self) -> bool {
+        matches!(
+            self.connection.connection_state(),
+            ConnectionState::Idle | ConnectionState::Failed
+        )
+    }
+}
+
+impl Node for Endpoint {
+    fn process_frame(&self, frame: &[u8]) {
+        if let Err(e) = self.process_packet(frame) {
+            trace!("Failed parsing packet: {}", e);
+        }
+    }
+
+    fn next_frame(&self) -> Option<Vec<u8>> {
+        self.get_packet()
+    }
+
+    fn tick(&self) {
+        Endpoint::tick(self);
+    }
+}
+
+/// Owns the threads moving frames between a `Transport` and a `Node`. One thread handles received
+/// frames, the other one ticks the node and sends its frames. Both stop once the node is finished
+/// or `stop` is called.
+pub struct Runner {
+    terminate: Arc<AtomicBool>,
+    threads: Vec<(String, JoinHandle<()>)>,
+}
+
+impl Runner {
+    pub fn new(transport: Arc<dyn Transport>, node: Arc<dyn Node>) -> Self {
+        let terminate = Arc::new(AtomicBool::new(false));
+
+        let rx_thread = {
+            let (transport, node, terminate) = (transport.clone(), node.clone(), terminate.clone());
+            thread::spawn(move || {
+                while !terminate.load(Ordering::Relaxed) && !node.finished() {
+                    while let Some(frame) = transport.receive() {
+                        node.process_frame(&frame);
+                    }
+                    thread::yield_now();
+                }
+                info!("RX Thread done.");
+            })
+        };
+
+        let tx_thread = {
+            let terminate = terminate.clone();
+            thread::spawn(move || {
+                while !terminate.load(Ordering::Relaxed) && !node.finished() {
+                    node.tick();
+
+                    while let Some(frame) = node.next_frame() {
+                        if let Err(e) = transport.send(frame) {
+                            error!("Failed to send frame: {}", e);
+                        }
+                    }
+                    thread::yield_now();
+                }
+                info!("TX Thread done.");
+            })
+        };
+
+        Runner {
+            terminate,
+            threads: vec![("RX".to_string(), rx_thread), ("TX".to_string(), tx_thread)],
+        }
+    }
+
+    /// Asks the threads to stop without waiting for them.
+    pub fn stop(&self) {
+        self.terminate.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.threads.iter().all(|(_, t)| t.is_finished())
+    }
+
+    /// Waits for the threads to stop, either because the node is finished or `stop` was called.
+    pub fn join(mut self) -> Result<()> {
+        self.join_threads()
+    }
+
+    fn join_threads(&mut self) -> Result<()> {
+        let mut res = Ok(());
+        for (name, t) in self.threads.drain(..) {
+            if t.join().is_err() {
+                res = Err(Error::ThreadPanicked { name });
+            }
+        }
+        res
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        self.stop();
+        if let Err(e) = self.join_threads() {
+            error!("{}", e);
+        }
+    }
+}
@@ -8,22 +8,45 @@
 
 use std::time::{Duration, Instant};
 
-use crate::{cache::Cache, connection::Connection, operations::Operations};
+use crate::{
+    cache::Cache, config::ConnectionConfig, connection::Connection, operations::Operations,
+    responder::Responder,
+};
 
 #[derive(Debug, Snafu)]
 pub enum Error {}
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub struct Tick {
+/// Budget for a connection without progress, i.e., without receiving a valid packet while sent
+/// packets are waiting for an ack. Exhausting either limit fails the connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Liveness {
+    /// Number of resends without progress.
+    pub max_resends: u32,
+    /// Time without progress.
+    pub timeout: Duration,
+}
+
+/// Timers of a single virtual channel.
+#[derive(Default)]
+struct VcTimers {
     ack_required_since: Option<Instant>,
     resend_cooldown: Option<Instant>,
+}
+
+pub struct Tick {
+    vcs: Vec<VcTimers>,
     heartbeat: Option<Duration>,
     ack_only_timeout: Duration,
     last_send: Instant,
     resend_timeout: Duration,
     cycle: Duration,
     last_executed: Instant,
+    liveness: Option<Liveness>,
+    last_received: Option<Instant>,
+    waiting_since: Option<Instant>,
+    resends_without_progress: u32,
 }
 
 impl Tick {
@@ -37,70 +60,173 @@ impl Tick {
             ack_only_timeout,
             resend_timeout,
             heartbeat,
-            ack_required_since: None,
-            resend_cooldown: None,
+            vcs: Vec::new(),
             cycle,
             last_send: Instant::now(),
             last_executed: Instant::now(),
+            liveness: None,
+            last_received: None,
+            waiting_since: None,
+            resends_without_progress: 0,
         }
     }
 
+    /// Creates a tick using the timing and liveness parameters of `config`.
+    pub fn with_config(config: &ConnectionConfig) -> Self {
+        let tick = Self::new(
+            config.ack_only_timeout(),
+            config.resend_timeout(),
+            config.cycle(),
+            config.heartbeat(),
+        );
+        match config.liveness() {
+            Some(liveness) => tick.with_liveness(liveness),
+            None => tick,
+        }
+    }
+
+    /// Fails the connection and all outstanding operations once the peer stopped responding for
+    /// longer than `liveness` allows. Without a budget, packets are resent forever.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = Some(liveness);
+        self
+    }
+
     pub fn tick(&mut self, operations: &Operations, connection: &Connection, cache: &Cache) {
-        if self.last_executed.elapsed() < self.cycle {
+        if !self.cycle_elapsed() {
+            return;
+        }
+
+        // Background acquires still have to fail to wake up threads waiting for their lines
+        if !connection.is_failed() {
+            cache.process_probes(operations, connection.credits());
+        }
+        cache.process_background(operations, connection.credits());
+
+        self.tick_connection(operations, connection);
+    }
+
+    pub fn tick_responder(
+        &mut self,
+        operations: &Operations,
+        connection: &Connection,
+        responder: &Responder,
+    ) {
+        if !self.cycle_elapsed() {
             return;
         }
 
+        responder.process_requests(operations, connection);
+
+        self.tick_connection(operations, connection);
+    }
+
+    fn cycle_elapsed(&mut self) -> bool {
+        if self.last_executed.elapsed() < self.cycle {
+            return false;
+        }
+
         self.last_executed = Instant::now();
+        true
+    }
 
-        cache.process_probes(operations, connection.credits());
+    fn tick_connection(&mut self, operations: &Operations, connection: &Connection) {
+        if connection.is_failed() || !self.check_liveness(operations, connection) {
+            return;
+        }
 
-        self.set_ack_timeout(connection);
+        operations.process_responses(connection.credits());
 
-        self.check_send(operations, connection);
+        // Every virtual channel sends at most one packet per cycle, so a busy channel does not
+        // hold back the others
+        self.vcs
+            .resize_with(connection.virtual_channels(), VcTimers::default);
+        for vc in 0..self.vcs.len() {
+            self.set_ack_timeout(connection, vc);
 
-        self.check_resend(connection);
+            self.check_send(operations, connection, vc);
+
+            self.check_resend(connection, vc);
+        }
     }
 
-    fn check_resend(&mut self, connection: &Connection) {
-        if self.reset_pending(connection) {
-            self.check_resend_cooldown();
-            self.do_resend(connection);
+    fn check_resend(&mut self, connection: &Connection, vc: usize) {
+        if self.reset_pending(connection, vc) {
+            self.check_resend_cooldown(vc);
+            self.do_resend(connection, vc);
         }
     }
 
-    fn do_resend(&mut self, connection: &Connection) {
-        if self.resend_cooldown.is_none() && connection.resend().is_ok() {
-            self.resend_cooldown = Some(Instant::now());
+    fn do_resend(&mut self, connection: &Connection, vc: usize) {
+        if self.vcs[vc].resend_cooldown.is_none() && connection.resend(vc as u8).is_ok() {
+            self.vcs[vc].resend_cooldown = Some(Instant::now());
+            self.resends_without_progress += 1;
+        }
+    }
+
+    /// Returns false after failing the connection.
+    fn check_liveness(&mut self, operations: &Operations, connection: &Connection) -> bool {
+        let liveness = match self.liveness {
+            Some(l) => l,
+            None => return true,
+        };
+
+        let received = connection.last_message_received_at();
+        if self.last_received != Some(received) {
+            self.last_received = Some(received);
+            self.waiting_since = None;
+            self.resends_without_progress = 0;
+        }
+
+        if connection.unacked_packets() == 0 {
+            self.waiting_since = None;
+            self.resends_without_progress = 0;
+            return true;
+        }
+
+        let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+        if self.resends_without_progress >= liveness.max_resends
+            || waiting_since.elapsed() >= liveness.timeout
+        {
+            connection.fail();
+            operations.connection_lost();
+            return false;
         }
+        true
     }
 
-    fn check_resend_cooldown(&mut self) {
-        if let Some(t) = self.resend_cooldown {
+    fn check_resend_cooldown(&mut self, vc: usize) {
+        if let Some(t) = self.vcs[vc].resend_cooldown {
             if t.elapsed() >= self.resend_timeout {
-                self.resend_cooldown = None;
+                self.vcs[vc].resend_cooldown = None;
             }
         }
     }
 
-    fn reset_pending(&mut self, connection: &Connection) -> bool {
-        connection.last_message_received_at().elapsed() >= self.resend_timeout
-            || connection.resend_outstanding()
+    fn reset_pending(&mut self, connection: &Connection, vc: usize) -> bool {
+        connection.last_progress_at(vc as u8).elapsed() >= self.resend_timeout
+            || connection.resend_outstanding(vc as u8)
     }
 
-    fn check_send(&mut self, operations: &Operations, connection: &Connection) {
-        if !(self.send_required(operations, connection) || self.heartbeat()) {
+    /// Heartbeats are only sent on VC 0.
+    fn check_send(&mut self, operations: &Operations, connection: &Connection, vc: usize) {
+        if !(self.send_required(operations, connection, vc) || (vc == 0 && self.heartbeat())) {
             return;
         }
 
+        let mut queue = operations
+            .operations_outstanding(vc as u8)
+            .map(|q| q.lock());
         if connection
             .send_packet(
-                Some(&mut operations.operations_outstanding().lock()),
+                vc as u8,
+                queue.as_deref_mut(),
                 operations.num_outstanding() != 0,
             )
             .is_ok()
         {
             self.last_send = Instant::now();
-            self.ack_required_since = None;
+            self.vcs[vc].ack_required_since = None;
         }
     }
 
@@ -112,17 +238,30 @@ impl Tick {
         }
     }
 
-    fn send_required(&mut self, operations: &Operations, connection: &Connection) -> bool {
-        !operations.operations_outstanding().lock().is_empty()
-            || connection.send_outstanding()
-            || self.ack_required_since.unwrap_or(Instant::now()).elapsed() >= self.ack_only_timeout
+    fn send_required(
+        &mut self,
+        operations: &Operations,
+        connection: &Connection,
+        vc: usize,
+    ) -> bool {
+        operations
+            .operations_outstanding(vc as u8)
+            .is_some_and(|q| !q.lock().is_empty())
+            || connection.send_outstanding(vc as u8)
+            || self.vcs[vc]
+                .ack_required_since
+                .unwrap_or(Instant::now())
+                .elapsed()
+                >= self.ack_only_timeout
     }
 
-    fn set_ack_timeout(&mut self, connection: &Connection) {
-        if self.ack_required_since.is_none() && connection.ack_outstanding() {
-            self.ack_required_since = Some(Instant::now());
-        } else if self.ack_required_since.is_some() && !connection.ack_outstanding() {
-            self.ack_required_since = None;
+    fn set_ack_timeout(&mut self, connection: &Connection, vc: usize) {
+        let timers = &mut self.vcs[vc];
+        let outstanding = connection.ack_outstanding(vc as u8);
+        if timers.ack_required_since.is_none() && outstanding {
+            timers.ack_required_since = Some(Instant::now());
+        } else if timers.ack_required_since.is_some() && !outstanding {
+            timers.ack_required_since = None;
         }
     }
 }
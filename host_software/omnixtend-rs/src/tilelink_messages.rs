@@ -96,6 +96,35 @@ pub enum OmnixtendPermissionChangeReport {
     NtoN = 5,
 }
 
+/// Param of an ArithmeticData message on channel A.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OmnixtendArithmetic {
+    Min = 0,
+    Max = 1,
+    MinU = 2,
+    MaxU = 3,
+    Add = 4,
+}
+
+/// Param of an Intent message on channel A.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OmnixtendHint {
+    PrefetchRead = 0,
+    PrefetchWrite = 1,
+}
+
+/// Param of a LogicalData message on channel A.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OmnixtendLogical {
+    Xor = 0,
+    Or = 1,
+    And = 2,
+    Swap = 3,
+}
+
 pub fn get_resulting_permission(g: &OmnixtendPermissionChangeGrow) -> OmnixtendPermissionChangeCap {
     if *g == OmnixtendPermissionChangeGrow::NtoB {
         OmnixtendPermissionChangeCap::ToB
@@ -150,6 +179,15 @@ pub fn get_permission_change(
     }
 }
 
+/// Permission held after a Release or ProbeAck with the given prune or report parameter.
+pub fn get_permission_after_change(param: u8) -> OmnixtendPermissionChangeCap {
+    match param {
+        0 | 4 => OmnixtendPermissionChangeCap::ToB,
+        3 => OmnixtendPermissionChangeCap::ToT,
+        _ => OmnixtendPermissionChangeCap::ToN,
+    }
+}
+
 #[repr(u8)]
 pub enum OmnixtendMessageType {
     NORMAL = 0,
@@ -167,7 +205,7 @@ impl From<u64> for OmnixtendChannel {
             3 => OmnixtendChannel::C,
             4 => OmnixtendChannel::D,
             5 => OmnixtendChannel::E,
-            _ => panic!("Unknown channel {}", m),
+            _default => OmnixtendChannel::INVALID,
         }
     }
 }
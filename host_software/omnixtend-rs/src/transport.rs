@@ -0,0 +1,395 @@
+/*
+    SPDX-License-Identifier: Apache License 2.0
+
+    SPDX-FileCopyrightText: 2022 Western Digital Corporation or its affiliates.
+
+    Author: Jaco Hofmann (jaco.hofmann@wdc.com)
+*/
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{ErrorKind, Read},
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::queue::SegQueue;
+use parking_lot::Mutex;
+use pnet::{
+    datalink::{self, Channel::Ethernet, DataLinkReceiver, DataLinkSender},
+    util::MacAddr,
+};
+use snafu::ResultExt;
+
+use crate::ethernet::ETHERNET_HEADER_SIZE;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("IO Error: {}", source))]
+    IOError { source: std::io::Error },
+
+    #[snafu(display("Could not find interface: {}", name))]
+    InterfaceNotFound { name: String },
+
+    #[snafu(display("Unhandled channel type."))]
+    UnhandledChannelType {},
+
+    #[snafu(display("Could not read pcap file {}: {}", name, source))]
+    PcapIOError {
+        name: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Invalid pcap file: {}", reason))]
+    InvalidPcap { reason: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Moves packets, i.e., raw Ethernet frames or UDP payloads depending on the connection
+/// encapsulation, between the OmniXtend protocol handling and the outside world.
+/// Implementations have to be usable from multiple threads at once, `receive` must not block.
+pub trait Transport: Send + Sync {
+    /// Queues `frame` for transmission.
+    fn send(&self, frame: Vec<u8>) -> Result<()>;
+
+    /// Returns the next received frame if one is available.
+    fn receive(&self) -> Option<Vec<u8>>;
+
+    /// MAC address of the underlying interface, if there is one.
+    fn mac(&self) -> Option<MacAddr> {
+        None
+    }
+
+    /// Largest frame in bytes, including the Ethernet header, the underlying interface can send,
+    /// if known. See `ConnectionConfig::with_transport_mtu`.
+    fn max_frame_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Transport using a raw pnet datalink channel. Sending and receiving is done by two background
+/// threads which are stopped when the transport is dropped.
+pub struct DatalinkTransport {
+    tx_queue: Arc<SegQueue<Vec<u8>>>,
+    rx_queue: Arc<SegQueue<Vec<u8>>>,
+    terminate: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    mac: Option<MacAddr>,
+    max_frame_size: Option<usize>,
+}
+
+impl DatalinkTransport {
+    pub fn new(interface: &str) -> Result<Self> {
+        Self::with_config(
+            interface,
+            datalink::Config {
+                write_buffer_size: 16384 * 16,
+                read_buffer_size: 16384 * 16,
+                read_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_config(interface: &str, config: datalink::Config) -> Result<Self> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface.trim())
+            .ok_or(Error::InterfaceNotFound {
+                name: interface.to_string(),
+            })?;
+
+        let (tx, rx) = match datalink::channel(&interface, config) {
+            Ok(Ethernet(tx, rx)) => (tx, rx),
+            Ok(_) => return Err(Error::UnhandledChannelType {}),
+            Err(e) => return Err(e).context(IOSnafu)?,
+        };
+
+        let tx_queue = Arc::new(SegQueue::new());
+        let rx_queue = Arc::new(SegQueue::new());
+        let terminate = Arc::new(AtomicBool::new(false));
+
+        let threads = vec![
+            Self::start_send_thread(tx_queue.clone(), terminate.clone(), tx),
+            Self::start_receive_thread(rx_queue.clone(), terminate.clone(), rx),
+        ];
+
+        Ok(DatalinkTransport {
+            tx_queue,
+            rx_queue,
+            terminate,
+            threads,
+            mac: interface.mac,
+            max_frame_size: interface_mtu(&interface.name).map(|mtu| mtu + ETHERNET_HEADER_SIZE),
+        })
+    }
+
+    fn start_send_thread(
+        tx_queue: Arc<SegQueue<Vec<u8>>>,
+        terminate: Arc<AtomicBool>,
+        mut tx: Box<dyn DataLinkSender>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            while !terminate.load(Ordering::Relaxed) {
+                while let Some(x) = tx_queue.pop() {
+                    tx.send_to(&x[..], None);
+                }
+                thread::yield_now();
+            }
+            info!("TX Thread done.");
+        })
+    }
+
+    fn start_receive_thread(
+        rx_queue: Arc<SegQueue<Vec<u8>>>,
+        terminate: Arc<AtomicBool>,
+        mut rx: Box<dyn DataLinkReceiver>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            while !terminate.load(Ordering::Relaxed) {
+                if let Ok(packet) = rx.next() {
+                    rx_queue.push(Vec::from(packet));
+                }
+                thread::yield_now();
+            }
+            info!("RX Thread done.");
+        })
+    }
+}
+
+impl Drop for DatalinkTransport {
+    fn drop(&mut self) {
+        self.terminate.store(true, Ordering::Relaxed);
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                error!("Datalink thread panicked.");
+            }
+        }
+    }
+}
+
+impl Transport for DatalinkTransport {
+    fn send(&self, frame: Vec<u8>) -> Result<()> {
+        self.tx_queue.push(frame);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        self.rx_queue.pop()
+    }
+
+    fn mac(&self) -> Option<MacAddr> {
+        self.mac
+    }
+
+    fn max_frame_size(&self) -> Option<usize> {
+        self.max_frame_size
+    }
+}
+
+/// pnet does not report the MTU of an interface, Linux provides it through sysfs.
+fn interface_mtu(name: &str) -> Option<usize> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// In-process transport. Frames sent on one side of a pair are received on the other side.
+pub struct LoopbackTransport {
+    tx_queue: Arc<SegQueue<Vec<u8>>>,
+    rx_queue: Arc<SegQueue<Vec<u8>>>,
+}
+
+impl LoopbackTransport {
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(SegQueue::new());
+        let b = Arc::new(SegQueue::new());
+        (
+            LoopbackTransport {
+                tx_queue: a.clone(),
+                rx_queue: b.clone(),
+            },
+            LoopbackTransport {
+                tx_queue: b,
+                rx_queue: a,
+            },
+        )
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&self, frame: Vec<u8>) -> Result<()> {
+        self.tx_queue.push(frame);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        self.rx_queue.pop()
+    }
+}
+
+/// Transport carrying OmniXtend packets in UDP datagrams between a local and a remote socket
+/// address. Has to be used with connections created by `Connection::new_udp`.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn new(local: SocketAddr, remote: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(local).context(IOSnafu)?;
+        // Only datagrams from the remote address are received on a connected socket
+        socket.connect(remote).context(IOSnafu)?;
+        socket.set_nonblocking(true).context(IOSnafu)?;
+        Ok(UdpTransport { socket })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().context(IOSnafu)
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, frame: Vec<u8>) -> Result<()> {
+        match self.socket.send(&frame) {
+            Ok(_) => Ok(()),
+            // The remote side might not be up yet, lost packets are resent later on
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(()),
+            Err(e) => Err(e).context(IOSnafu),
+        }
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut buf = [0; 65536];
+        match self.socket.recv(&mut buf) {
+            Ok(len) => Some(buf[..len].to_vec()),
+            Err(e) => {
+                if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::ConnectionRefused {
+                    error!("Failed to receive datagram: {}", e);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Replays the Ethernet frames of a pcap file as received frames. Sent frames are recorded and
+/// can be retrieved with `sent`. With `realtime` set, the original time between frames is kept.
+pub struct PcapTransport {
+    frames: Mutex<VecDeque<(Duration, Vec<u8>)>>,
+    sent: Mutex<Vec<Vec<u8>>>,
+    started: Mutex<Option<Instant>>,
+    realtime: bool,
+}
+
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+impl PcapTransport {
+    pub fn open<P: AsRef<Path>>(path: P, realtime: bool) -> Result<Self> {
+        let name = path.as_ref().to_string_lossy().to_string();
+        let mut data = Vec::new();
+        File::open(path.as_ref())
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .context(PcapIOSnafu { name })?;
+        Self::from_bytes(&data, realtime)
+    }
+
+    pub fn from_bytes(data: &[u8], realtime: bool) -> Result<Self> {
+        let header = data.get(..24).ok_or(Error::InvalidPcap {
+            reason: "File too short for header.".to_string(),
+        })?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (little_endian, nanos) = match magic {
+            0xa1b2c3d4 => (true, false),
+            0xa1b23c4d => (true, true),
+            0xd4c3b2a1 => (false, false),
+            0x4d3cb2a1 => (false, true),
+            _ => Err(Error::InvalidPcap {
+                reason: format!("Unknown magic 0x{:X}.", magic),
+            })?,
+        };
+        let read_u32 = |b: &[u8]| {
+            let b = b.try_into().unwrap();
+            if little_endian {
+                u32::from_le_bytes(b)
+            } else {
+                u32::from_be_bytes(b)
+            }
+        };
+
+        let linktype = read_u32(&header[20..24]);
+        if linktype != PCAP_LINKTYPE_ETHERNET {
+            Err(Error::InvalidPcap {
+                reason: format!("Unsupported link type {}.", linktype),
+            })?;
+        }
+
+        let mut frames = VecDeque::new();
+        let mut first = None;
+        let mut pos = 24;
+        while pos < data.len() {
+            let record = data.get(pos..pos + 16).ok_or(Error::InvalidPcap {
+                reason: format!("Truncated record header at {}.", pos),
+            })?;
+            let ts = Duration::from_secs(read_u32(&record[0..4]) as u64)
+                + if nanos {
+                    Duration::from_nanos(read_u32(&record[4..8]) as u64)
+                } else {
+                    Duration::from_micros(read_u32(&record[4..8]) as u64)
+                };
+            let len = read_u32(&record[8..12]) as usize;
+            pos += 16;
+            let frame = data.get(pos..pos + len).ok_or(Error::InvalidPcap {
+                reason: format!("Truncated record data at {}.", pos),
+            })?;
+            pos += len;
+
+            let first = *first.get_or_insert(ts);
+            frames.push_back((ts.saturating_sub(first), frame.to_vec()));
+        }
+
+        Ok(PcapTransport {
+            frames: Mutex::new(frames),
+            sent: Mutex::new(Vec::new()),
+            started: Mutex::new(None),
+            realtime,
+        })
+    }
+
+    /// Number of frames that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.frames.lock().len()
+    }
+
+    /// Frames sent so far.
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.sent.lock().clone()
+    }
+}
+
+impl Transport for PcapTransport {
+    fn send(&self, frame: Vec<u8>) -> Result<()> {
+        self.sent.lock().push(frame);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut frames = self.frames.lock();
+        if self.realtime {
+            let started = *self.started.lock().get_or_insert_with(Instant::now);
+            if frames.front()?.0 > started.elapsed() {
+                return None;
+            }
+        }
+        frames.pop_front().map(|(_, f)| f)
+    }
+}
@@ -6,12 +6,16 @@
     Author: Jaco Hofmann (jaco.hofmann@wdc.com)
 */
 
-use std::collections::VecDeque;
+use std::{collections::VecDeque, task::Waker};
 
+use parking_lot::Mutex;
 use snafu::ResultExt;
 
-use crate::ConnectionSnafu;
-use crate::{cache::Cache, channels::Channel, connection::Connection, operations::Operations};
+use crate::{
+    cache::Cache, channels::Channel, connection::Connection, operations::Operations,
+    responder::Responder,
+};
+use crate::{ChannelSnafu, ConnectionSnafu};
 
 pub fn chunkize_packet(p: &[u8]) -> VecDeque<u64> {
     let mut v = VecDeque::new();
@@ -27,29 +31,73 @@ pub fn chunkize_packet(p: &[u8]) -> VecDeque<u64> {
     v
 }
 
+/// Wakers of the futures waiting for a resource, at most one per future.
+#[derive(Default)]
+pub(crate) struct Wakers(Mutex<Vec<(usize, Waker)>>);
+
+impl Wakers {
+    /// Registers the waker of future `id`, replacing the one stored by an earlier poll.
+    pub(crate) fn register(&self, id: usize, waker: &Waker) {
+        let mut wakers = self.0.lock();
+        match wakers.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, w)) => w.clone_from(waker),
+            None => wakers.push((id, waker.clone())),
+        }
+    }
+
+    pub(crate) fn remove(&self, id: usize) {
+        self.0.lock().retain(|(i, _)| *i != id);
+    }
+
+    pub(crate) fn wake(&self) {
+        self.0.lock().drain(..).for_each(|(_, w)| w.wake());
+    }
+}
+
 pub fn process_packet(
     v: &[u8],
     connection: &Connection,
     cache: &Cache,
     operations: &Operations,
 ) -> crate::Result<()> {
-    match connection.process_packets(v).context(ConnectionSnafu) {
-        Ok(v) => {
-            let (mut credits, mut probes, mut responses) = Channel::process_messages(&v[..])
-                .unwrap_or_else(|_f| (Vec::new(), Vec::new(), Vec::new()));
-            credits
-                .drain(..)
-                .for_each(|(chan, credits)| connection.add_receive_credits(chan, credits));
-
-            probes.drain(..).for_each(|p| {
-                cache.add_probe(p);
-            });
-
-            responses.drain(..).for_each(|(source, sink, result)| {
-                operations.complete(source, sink, result);
-            });
-            Ok(())
-        }
-        Err(e) => Err(e)?,
+    let (vc, v) = connection.process_packets(v).context(ConnectionSnafu)?;
+    // Ack only, replicated and out of sequence packets carry no messages
+    if v.is_empty() {
+        return Ok(());
     }
+    // The sequence number already accounts for this packet, so the messages in front of a
+    // malformed one are applied before reporting it
+    let (mut credits, mut probes, mut responses, res) = Channel::process_messages(&v[..]);
+    credits
+        .drain(..)
+        .for_each(|(chan, credits)| connection.add_receive_credits(vc, chan, credits));
+
+    probes.drain(..).for_each(|p| {
+        cache.add_probe(p);
+    });
+
+    responses.drain(..).for_each(|r| {
+        operations.complete(r);
+    });
+    res.context(ChannelSnafu)
+}
+
+pub fn process_request_packet(
+    v: &[u8],
+    connection: &Connection,
+    responder: &Responder,
+) -> crate::Result<()> {
+    let (vc, v) = connection.process_packets(v).context(ConnectionSnafu)?;
+    if v.is_empty() {
+        return Ok(());
+    }
+    let (mut credits, mut requests, res) = Channel::process_requests(&v[..]);
+    credits
+        .drain(..)
+        .for_each(|(chan, credits)| connection.add_receive_credits(vc, chan, credits));
+
+    requests.drain(..).for_each(|r| {
+        responder.add_request(vc, r);
+    });
+    res.context(ChannelSnafu)
 }
@@ -8,10 +8,11 @@
 
 use std::time::Duration;
 
-use omnixtend_rs::cache::{Cache, CacheStatus};
+use omnixtend_rs::cache::{Cache, CacheStats, CacheStatus};
+use omnixtend_rs::config::ConnectionConfig;
 use omnixtend_rs::connection::ConnectionState;
 use omnixtend_rs::operations::{Operations, ReadOp, TLOperations, TLResult, WriteOp};
-use omnixtend_rs::tick::Tick;
+use omnixtend_rs::tick::{Liveness, Tick};
 use omnixtend_rs::utils::process_packet;
 use parking_lot::Mutex;
 use pnet::util::MacAddr;
@@ -23,6 +24,15 @@ use crate::Error;
 use crate::OperationsSnafu;
 use crate::Result;
 
+/// Commands fail instead of blocking the TUI if the endpoint stops responding.
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connection is marked as failed once the endpoint stopped responding.
+const LIVENESS: Liveness = Liveness {
+    max_resends: 20,
+    timeout: Duration::from_secs(3),
+};
+
 pub struct Connection {
     connection: omnixtend_rs::connection::Connection,
     cache: Cache,
@@ -39,25 +49,38 @@ impl Connection {
         other_mac: &MacAddr,
         addr: u64,
         size: u64,
-        ox10mode: bool,
+        config: ConnectionConfig,
+        udp: bool,
     ) -> Result<Self> {
-        let s = omnixtend_rs::connection::Connection::new(ox10mode, id, *my_mac, *other_mac);
+        let config = config.with_liveness(LIVENESS);
+        let s = if udp {
+            omnixtend_rs::connection::Connection::udp_with_config(config.clone(), id)
+        } else {
+            omnixtend_rs::connection::Connection::with_config(
+                config.clone(),
+                id,
+                *my_mac,
+                *other_mac,
+            )
+        };
         s.establish_connection();
+        let operations = Operations::with_config(&config);
+        operations.set_timeout(Some(OPERATION_TIMEOUT));
         Ok(Connection {
             connection: s,
             cache: Cache::new(id),
-            operations: Operations::new(),
+            operations,
             addr: addr,
             size: size,
-            tick: Mutex::new(Tick::new(
-                Duration::from_millis(1),
-                Duration::from_millis(100),
-                Duration::from_micros(1),
-                Some(Duration::from_secs(1)),
-            )),
+            tick: Mutex::new(Tick::with_config(&config)),
         })
     }
 
+    /// Called once from the tick thread when the endpoint stopped responding.
+    pub fn set_failure_handler(&self, handler: impl Fn() + Send + Sync + 'static) {
+        self.connection.set_failure_handler(handler);
+    }
+
     pub fn status(&self) -> ConnectionState {
         self.connection.connection_state()
     }
@@ -90,6 +113,7 @@ impl Connection {
         {
             error!("Could not close connection: {}", e);
         }
+        self.operations.cancel_all();
     }
 
     pub fn get_state(&self, mac: &MacAddr) -> TuiConnectionState {
@@ -106,6 +130,7 @@ impl Connection {
             we_acked: status.we_acked() as u64,
             last_msg_in_micros: status.last_msg_in_micros(),
             last_msg_out_micros: status.last_msg_out_micros(),
+            stats: self.connection.stats(),
         }
     }
 
@@ -113,6 +138,10 @@ impl Connection {
         self.cache.retrieve_overview()
     }
 
+    pub fn get_cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     pub fn deals_with(&self, addr: u64) -> bool {
         addr >= self.addr && addr < self.addr + self.size
     }
@@ -125,6 +154,14 @@ impl Connection {
             .context(CacheSnafu)
     }
 
+    pub fn cache_flush(&self) -> Result<()> {
+        self.reject_inactive()?;
+
+        self.cache
+            .flush_all(&self.operations, self.connection.credits())
+            .context(CacheSnafu)
+    }
+
     fn reject_inactive(&self) -> Result<()> {
         if !self.connection.is_active() {
             return Err(Error::ConnectionNotActive {});
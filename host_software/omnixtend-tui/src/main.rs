@@ -23,14 +23,17 @@ use crossbeam::channel::bounded;
 use crossbeam::channel::SendError;
 use dashmap::DashMap;
 use log::SetLoggerError;
+use omnixtend_rs::cache::CacheStats;
+use omnixtend_rs::config::ConnectionConfig;
 use omnixtend_rs::connection::ConnectionState;
-use omnixtend_rs::omnixtend::OmnixtendPacket;
-use pnet::packet::ethernet::EtherType;
-use pnet::packet::ethernet::EthernetPacket;
-use pnet::packet::Packet;
+use omnixtend_rs::runner::Runner;
+use omnixtend_rs::transport::DatalinkTransport;
+use omnixtend_rs::transport::Transport;
+use omnixtend_rs::transport::UdpTransport;
 use pnet::util::{MacAddr, ParseMacAddrErr};
 use snafu::ResultExt;
 use snafu::Snafu;
+use std::net::SocketAddr;
 use std::num::ParseIntError;
 use std::str;
 use std::sync::atomic::AtomicBool;
@@ -92,6 +95,14 @@ pub enum Error {
     #[snafu(display("Omnixtend-rs error: {}", source))]
     OmnixtendError { source: omnixtend_rs::Error },
 
+    #[snafu(display("Invalid connection parameters: {}", source))]
+    ConfigError { source: omnixtend_rs::config::Error },
+
+    #[snafu(display("Transport error: {}", source))]
+    TransportError {
+        source: omnixtend_rs::transport::Error,
+    },
+
     #[snafu(display("Cache error: {}", source))]
     CacheError { source: omnixtend_rs::cache::Error },
 
@@ -120,6 +131,12 @@ pub enum Error {
 
     #[snafu(display("Cannot perform operation on inactive connection."))]
     ConnectionNotActive {},
+
+    #[snafu(display("Either an interface or UDP addresses are required."))]
+    NoTransport {},
+
+    #[snafu(display("Only a single connection is supported over UDP."))]
+    UdpSingleConnection {},
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -129,47 +146,43 @@ fn run(opts: &Opts) -> Result<()> {
 
     let tui = Arc::new(Tui::new()?);
 
-    let network = Network::new(&opts.interface)?;
+    let transport: Arc<dyn Transport> = match (&opts.interface, opts.udp_local, opts.udp_remote) {
+        (_, Some(local), Some(remote)) => {
+            Arc::new(UdpTransport::new(local, remote).context(TransportSnafu)?)
+        }
+        (Some(i), _, _) => Arc::new(DatalinkTransport::new(i).context(TransportSnafu)?),
+        _ => Err(Error::NoTransport {})?,
+    };
+    let udp = opts.udp_remote.is_some();
+
+    let config = ConnectionConfig::default().with_compat_mode(opts.ox10_mode);
+    let config = match opts.mtu {
+        Some(mtu) => config.with_mtu(mtu),
+        None if opts.standard_frames => Ok(config.with_standard_frames()),
+        None => config.with_transport_mtu(transport.as_ref()),
+    }
+    .and_then(|c| c.with_max_messages_per_frame(opts.max_tl_per_frame))
+    .and_then(|c| match opts.vlan {
+        Some(id) => c.with_vlan(id, opts.vlan_pcp),
+        None => Ok(c),
+    })
+    .context(ConfigSnafu)?;
 
-    let my_mac = network.mac();
+    let my_mac = transport.mac().unwrap_or(MacAddr(0, 0, 0, 0, 0, 1));
 
     let connections: Arc<DashMap<MacAddr, Connection>> = Arc::new(DashMap::new());
 
     let ctrl_c_pressed = Arc::new(AtomicBool::new(false));
 
-    let ctrl_c_local = ctrl_c_pressed.clone();
-    let connections_local = connections.clone();
-    let connection_thread = thread::spawn(move || loop {
-        if let Some(x) = network.get_packet() {
-            if let Some(p) = EthernetPacket::new(&x[..]) {
-                if p.get_ethertype() == EtherType(0xAAAA) && p.get_destination() == my_mac {
-                    if let Some(c) = connections_local.get(&p.get_source()) {
-                        c.process_packet(&x[..]);
-                    } else {
-                        info!(
-                            "Possibly stale connection: {:?} {:?}",
-                            p,
-                            OmnixtendPacket::new(&p.payload())
-                        );
-                    }
-                }
-            }
-        }
-
-        for k in connections_local.iter() {
-            if let Some(p) = k.value().get_packet() {
-                network.put_packet(p);
-            }
-
-            k.value().tick();
-        }
-
-        thread::yield_now();
-
-        if ctrl_c_local.load(Ordering::Relaxed) {
-            break;
-        }
-    });
+    let runner = Runner::new(
+        transport,
+        Arc::new(Network::new(
+            my_mac,
+            udp,
+            config.ether_type(),
+            connections.clone(),
+        )),
+    );
 
     let ctrl_c_local = ctrl_c_pressed.clone();
     let connections_local = connections.clone();
@@ -186,9 +199,11 @@ fn run(opts: &Opts) -> Result<()> {
 
             let mut constates = Vec::new();
             let mut cachestates = Vec::new();
+            let mut cachestats = CacheStats::default();
             for k in connections_local.iter() {
                 constates.push(k.value().get_state(k.key()));
                 cachestates.append(&mut k.value().get_cache_state());
+                cachestats += k.value().get_cache_stats();
             }
 
             tui_local
@@ -197,6 +212,7 @@ fn run(opts: &Opts) -> Result<()> {
                     eventsps_local.load(Ordering::Relaxed),
                     &constates,
                     &cachestates,
+                    &cachestats,
                 )
                 .unwrap_or_else(|err| {
                     tui_local
@@ -236,7 +252,6 @@ fn run(opts: &Opts) -> Result<()> {
     let connections_local = connections.clone();
     let ctrl_c_local = ctrl_c_pressed.clone();
     let tui_local = tui.clone();
-    let ox10mode = opts.ox10_mode;
     let operation_thread = thread::spawn(move || {
         let mut con_cntr = 0;
         loop {
@@ -249,7 +264,8 @@ fn run(opts: &Opts) -> Result<()> {
                     &connections_local,
                     &mut con_cntr,
                     my_mac,
-                    ox10mode,
+                    &config,
+                    udp,
                 )
                 .unwrap_or_else(|err| {
                     tui_local
@@ -305,7 +321,8 @@ fn run(opts: &Opts) -> Result<()> {
             }
         )
     });
-    connection_thread.join().unwrap_or_else(|e| {
+    runner.stop();
+    runner.join().unwrap_or_else(|e| {
         error!(
             "{}",
             Error::ThreadPanicError {
@@ -365,6 +382,11 @@ fn handle_tui_events(
                 .send(CmdlineEvents::CacheReleaseAll)
                 .context(ThreadSendSnafu)?;
         }
+        CmdlineEvents::CacheFlushAll => {
+            event_tx_send
+                .send(CmdlineEvents::CacheFlushAll)
+                .context(ThreadSendSnafu)?;
+        }
         CmdlineEvents::CacheRead(addr) => {
             event_tx_send
                 .send(CmdlineEvents::CacheRead(addr))
@@ -385,6 +407,7 @@ fn handle_tui_events(
             tui_local.log_message("(cr)ead 0xADDR (Cached read)", log::Level::Info)?;
             tui_local.log_message("(cw)rite 0xADDR 0xDATA (Cached write)", log::Level::Info)?;
             tui_local.log_message("(cd)estroy 0xADDR (Cache release all)", log::Level::Info)?;
+            tui_local.log_message("(cf)lush (Write back dirty cache lines)", log::Level::Info)?;
         }
     }
     Ok(false)
@@ -396,16 +419,34 @@ fn handle_connection_events(
     connections_local: &Arc<DashMap<MacAddr, Connection>>,
     con_cntr: &mut u8,
     my_mac: MacAddr,
-    ox10mode: bool,
+    config: &ConnectionConfig,
+    udp: bool,
 ) -> Result<()> {
     Ok(match e {
         CmdlineEvents::Connect(mac) => {
             let c = connections_local;
+            if udp && !c.is_empty() {
+                return Err(Error::UdpSingleConnection {});
+            }
             if !c.contains_key(&mac) {
-                c.insert(
-                    mac,
-                    Connection::new(*con_cntr, &my_mac, &mac, 0, 8 * 1024 * 1024, ox10mode)?,
-                );
+                let con = Connection::new(
+                    *con_cntr,
+                    &my_mac,
+                    &mac,
+                    0,
+                    8 * 1024 * 1024,
+                    config.clone(),
+                    udp,
+                )?;
+                let tui_failed = tui.clone();
+                con.set_failure_handler(move || {
+                    if let Err(e) =
+                        tui_failed.log_message(&format!("LOST {}", mac), log::Level::Error)
+                    {
+                        error!("Could not log connection loss: {:?}", e);
+                    }
+                });
+                c.insert(mac, con);
                 *con_cntr += 1;
                 tui.log_message(&format!("CON {}", mac), log::Level::Info)?;
             }
@@ -499,6 +540,15 @@ fn handle_connection_events(
                 }
             }
         }
+        CmdlineEvents::CacheFlushAll => {
+            for c in connections_local.iter() {
+                if let Err(e) = c.value().cache_flush() {
+                    tui.log_message(&format!("CF failed: {:?}", e), log::Level::Error)?;
+                } else {
+                    tui.log_message("CF", log::Level::Info)?;
+                }
+            }
+        }
         _ => (),
     })
 }
@@ -543,14 +593,34 @@ fn ensure_fps(target_fps: u64, start: Instant, frame_time: u128) -> f64 {
 #[derive(Parser)]
 #[clap(version = "0.4", author = "Jaco Hofmann <Jaco.Hofmann@wdc.com>")]
 struct Opts {
-    #[clap(short, long)]
-    interface: String,
+    #[clap(short, long, required_unless_present = "udp_remote")]
+    interface: Option<String>,
+    #[clap(long, requires = "udp_remote")]
+    udp_local: Option<SocketAddr>,
+    #[clap(long, requires = "udp_local")]
+    udp_remote: Option<SocketAddr>,
     #[clap(short, long, default_value = "30")]
     fps: u64,
     #[clap(short, long, default_value = "1000")]
     eventsps: u64,
     #[clap(long)]
     ox10_mode: bool,
+    /// Maximum frame size in bytes, has to match MAXIMUM_PACKET_SIZE of the endpoint. Defaults
+    /// to 9000 or less if the interface MTU is smaller
+    #[clap(long)]
+    mtu: Option<usize>,
+    /// Use standard 1500 byte frames, e.g., for switches without jumbo frame support
+    #[clap(long, conflicts_with = "mtu")]
+    standard_frames: bool,
+    /// Maximum number of TileLink messages per frame, see MAXIMUM_TL_PER_FRAME
+    #[clap(long, default_value = "64")]
+    max_tl_per_frame: usize,
+    /// Tag frames with this 802.1Q VLAN ID
+    #[clap(long)]
+    vlan: Option<u16>,
+    /// 802.1Q priority of tagged frames
+    #[clap(long, default_value = "0", requires = "vlan")]
+    vlan_pcp: u8,
 }
 
 fn main() {
@@ -22,8 +22,8 @@ use crossterm::terminal::disable_raw_mode;
 use crossterm::terminal::enable_raw_mode;
 use crossterm::terminal::EnterAlternateScreen;
 use crossterm::terminal::LeaveAlternateScreen;
-use omnixtend_rs::cache::CacheStatus;
-use omnixtend_rs::connection::ConnectionState;
+use omnixtend_rs::cache::{CacheStats, CacheStatus};
+use omnixtend_rs::connection::{ConnectionState, ConnectionStats};
 use omnixtend_rs::tilelink_messages::OmnixtendPermissionChangeCap;
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use pnet::util::MacAddr;
@@ -159,6 +159,7 @@ pub struct TuiConnectionState {
     pub they_acked: u64,
     pub last_msg_in_micros: Duration,
     pub last_msg_out_micros: Duration,
+    pub stats: ConnectionStats,
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -171,6 +172,7 @@ pub enum CmdlineEvents {
     Write(u64, u64),
     CacheRelease(u64),
     CacheReleaseAll,
+    CacheFlushAll,
     CacheRead(u64),
     CacheWrite(u64, u64),
     Help,
@@ -228,6 +230,7 @@ impl Tui {
         eventsps: u64,
         constates: &[TuiConnectionState],
         cachestates: &[CacheStatus],
+        cachestats: &CacheStats,
     ) -> Result<()> {
         let (cmdline, cursor) = self.get_cmdline_read()?.get();
         let v = self.get_log_read()?.clone();
@@ -244,6 +247,7 @@ impl Tui {
                     eventsps,
                     constates,
                     cachestates,
+                    cachestats,
                 )
                 .unwrap();
             })
@@ -260,6 +264,7 @@ impl Tui {
         eventsps: u64,
         constates: &[TuiConnectionState],
         cachestates: &[CacheStatus],
+        cachestats: &CacheStats,
     ) -> Result<()> {
         let chunks_v = Layout::default()
             .direction(Direction::Vertical)
@@ -292,7 +297,14 @@ impl Tui {
 
         f.render_widget(Self::render_connectionstatus(constates)?, chunks_status[0]);
 
-        f.render_widget(Self::render_cachestatus(cachestates)?, chunks_status[1]);
+        let chunks_cache = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(46), Constraint::Length(26)].as_ref())
+            .split(chunks_status[1]);
+
+        f.render_widget(Self::render_cachestatus(cachestates)?, chunks_cache[0]);
+
+        f.render_widget(Self::render_cachestats(cachestats), chunks_cache[1]);
 
         Ok(())
     }
@@ -375,6 +387,9 @@ impl Tui {
             "cda" | "cdestroyall" => {
                 event = CmdlineEvents::CacheReleaseAll;
             }
+            "cf" | "cflush" => {
+                event = CmdlineEvents::CacheFlushAll;
+            }
             "h" | "help" => {
                 event = CmdlineEvents::Help;
             }
@@ -514,6 +529,29 @@ impl Tui {
         .block(Block::default().title("Cache Status").borders(Borders::ALL)))
     }
 
+    fn render_cachestats(stats: &CacheStats) -> Table<'static> {
+        let rows = [
+            ("Hits", stats.hits),
+            ("Misses B", stats.misses_branch),
+            ("Misses T", stats.misses_trunk),
+            ("Acquires", stats.acquires),
+            ("Probes toT", stats.probes_to_t),
+            ("Probes toB", stats.probes_to_b),
+            ("Probes toN", stats.probes_to_n),
+            ("Probe WB", stats.probe_writebacks),
+            ("Releases", stats.releases),
+            ("Release WB", stats.release_writebacks),
+            ("Evictions", stats.evictions),
+        ]
+        .iter()
+        .map(|(name, v)| Row::new(vec![Cell::from(*name), Cell::from(format!("{}", v))]))
+        .collect::<Vec<_>>();
+        Table::new(rows, &[Constraint::Length(11), Constraint::Length(12)])
+            .column_spacing(1)
+            .style(Style::default())
+            .block(Block::default().title("Cache Stats").borders(Borders::ALL))
+    }
+
     fn render_connectionstatus(constates: &[TuiConnectionState]) -> Result<Table> {
         let mut coninfo = Vec::new();
         let header = Row::new(vec![
@@ -522,6 +560,9 @@ impl Tui {
             "TX/RX",
             "THEY/WE",
             "Out/Last",
+            "Frames",
+            "NAK/Resent",
+            "Stall/Drop",
         ]);
         for c in constates {
             let constyle = match c.state {
@@ -532,6 +573,7 @@ impl Tui {
                 ConnectionState::ClosedByHost => Color::LightRed,
                 ConnectionState::ClosedByHostIndicated => Color::Red,
                 ConnectionState::ClosedByClient => Color::Magenta,
+                ConnectionState::Failed => Color::DarkGray,
             };
             coninfo.push(
                 Row::new(vec![
@@ -548,6 +590,22 @@ impl Tui {
                         "{}\nI:{:.2?}O:{:.2?}",
                         c.outstanding, c.last_msg_in_micros, c.last_msg_out_micros
                     )),
+                    Cell::from(format!(
+                        "{}\n{}",
+                        c.stats.frames_sent, c.stats.frames_received
+                    )),
+                    Cell::from(format!(
+                        "{}/{}\n{} ({})",
+                        c.stats.naks_sent,
+                        c.stats.naks_received,
+                        c.stats.frames_resent,
+                        c.stats.resend_buffer_depth
+                    )),
+                    Cell::from(format!(
+                        "{}\n{}",
+                        c.stats.credit_stalls.iter().sum::<u64>(),
+                        c.stats.out_of_order_drops
+                    )),
                 ])
                 .height(2),
             );
@@ -560,6 +618,9 @@ impl Tui {
                 Constraint::Length(6),
                 Constraint::Length(6),
                 Constraint::Length(20),
+                Constraint::Length(10),
+                Constraint::Length(11),
+                Constraint::Length(10),
             ],
         )
         .header(header)
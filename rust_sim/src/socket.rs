@@ -7,10 +7,8 @@
 */
 
 use clap::Parser;
-use crossbeam::queue::SegQueue;
+use omnixtend_rs::transport::{DatalinkTransport, Transport};
 use parking_lot::Mutex;
-use pnet::datalink::Channel::Ethernet;
-use pnet::datalink::{self, DataLinkReceiver, DataLinkSender, NetworkInterface};
 use snafu::Snafu;
 use std::collections::VecDeque;
 use std::sync::{atomic::AtomicBool, Arc};
@@ -19,7 +17,6 @@ use std::{convert::TryInto, sync::RwLock};
 use std::{
     mem::take,
     sync::atomic::{AtomicU8, Ordering},
-    thread::{self, JoinHandle},
 };
 
 #[derive(Debug, Snafu)]
@@ -45,13 +42,12 @@ struct Opt {
 }
 
 pub struct Socket {
-    packets_out: Arc<SegQueue<Vec<u8>>>,
+    transport: DatalinkTransport,
+    reliability_send: f64,
+    reliability_receive: f64,
     packet_cur: Mutex<VecDeque<u64>>,
     packet_cur_mask: AtomicU8,
     packet_in: RwLock<Vec<u8>>,
-    packets_in: Arc<SegQueue<Vec<u8>>>,
-    send_thread: Option<JoinHandle<()>>,
-    receive_thread: Option<JoinHandle<()>>,
     pub active: Arc<AtomicBool>,
 }
 
@@ -64,12 +60,6 @@ impl Default for Socket {
 impl Drop for Socket {
     fn drop(&mut self) {
         self.active.store(false, Ordering::Relaxed);
-        if let Some(handle) = self.send_thread.take() {
-            handle.join().unwrap();
-        }
-        if let Some(handle) = self.receive_thread.take() {
-            handle.join().unwrap();
-        }
         println!("Socket done.");
     }
 }
@@ -95,19 +85,6 @@ impl Socket {
         println!("Parsed CLI {:?}", opt);
         let active = Arc::new(AtomicBool::new(true));
         let active_ctrlc = active.clone();
-        let packets_out = Arc::new(SegQueue::new());
-        let packets_in = Arc::new(SegQueue::new());
-
-        let interface_names_match = |iface: &NetworkInterface| iface.name == opt.ethernet_port;
-
-        let interfaces = datalink::interfaces();
-        info!("Available interfaces are: {:?}", interfaces);
-        let interface = interfaces
-            .into_iter()
-            .find(interface_names_match)
-            .unwrap_or_else(|| panic!("Interface {} not found", opt.ethernet_port));
-
-        println!("Selected interface {:?}", interface);
 
         // Create a new channel, dealing with layer 2 packets
         let config = pnet::datalink::Config {
@@ -117,11 +94,8 @@ impl Socket {
             ..Default::default()
         };
 
-        let (tx, rx) = match datalink::channel(&interface, config) {
-            Ok(Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => panic!("Can't open network interface."),
-            Err(e) => panic!("IO ERROR {}", e),
-        };
+        let transport = DatalinkTransport::with_config(&opt.ethernet_port, config)
+            .unwrap_or_else(|e| panic!("Can't open network interface: {}", e));
 
         ctrlc::set_handler(move || {
             info!("Ctrl-C pressed.");
@@ -132,70 +106,35 @@ impl Socket {
         println!("Socket active.");
 
         Socket {
-            send_thread: Some(Self::start_send_thread(
-                packets_out.clone(),
-                active.clone(),
-                tx,
-                opt.reliability_send,
-            )),
-            receive_thread: Some(Self::start_receive_thread(
-                packets_in.clone(),
-                active.clone(),
-                rx,
-                opt.reliability_receive,
-            )),
+            transport,
+            reliability_send: opt.reliability_send,
+            reliability_receive: opt.reliability_receive,
             packet_cur: Mutex::new(VecDeque::new()),
             packet_cur_mask: AtomicU8::new(0),
             packet_in: RwLock::new(Vec::new()),
-            packets_in,
-            packets_out,
             active,
         }
     }
 
-    pub fn start_send_thread(
-        packets_in: Arc<SegQueue<Vec<u8>>>,
-        active: Arc<AtomicBool>,
-        mut tx: Box<dyn DataLinkSender>,
-        reliability: f64,
-    ) -> JoinHandle<()> {
-        thread::spawn(move || {
-            info!("Hello from send thread...");
-            while active.load(Ordering::Relaxed) {
-                if let Some(pkt) = packets_in.pop() {
-                    if reliability != 1.0 && rand::random::<f64>() > reliability {
-                        trace!("Randomly dropping send packet...");
-                        continue;
-                    }
-                    tx.send_to(&pkt[..], None);
-                }
-                thread::yield_now();
+    fn receive(&self) -> Option<Vec<u8>> {
+        while let Some(pkt) = self.transport.receive() {
+            if self.reliability_receive != 1.0 && rand::random::<f64>() > self.reliability_receive {
+                trace!("Randomly dropping receive packet...");
+                continue;
             }
-            info!("Send thread done.");
-        })
+            return Some(pkt);
+        }
+        None
     }
 
-    pub fn start_receive_thread(
-        packets_out: Arc<SegQueue<Vec<u8>>>,
-        active: Arc<AtomicBool>,
-        mut rx: Box<dyn DataLinkReceiver>,
-        reliability: f64,
-    ) -> JoinHandle<()> {
-        thread::spawn(move || {
-            info!("Hello from receive thread...");
-            while active.load(Ordering::Relaxed) {
-                let p = rx.next();
-                if reliability != 1.0 && rand::random::<f64>() > reliability {
-                    trace!("Randomly dropping receive packet...");
-                    continue;
-                }
-                if let Ok(pkt) = p {
-                    packets_out.push(pkt.to_vec());
-                }
-                thread::yield_now();
-            }
-            info!("Receive thread done.");
-        })
+    fn send(&self, pkt: Vec<u8>) {
+        if self.reliability_send != 1.0 && rand::random::<f64>() > self.reliability_send {
+            trace!("Randomly dropping send packet...");
+            return;
+        }
+        if let Err(e) = self.transport.send(pkt) {
+            error!("Failed to send packet: {}", e);
+        }
     }
 
     pub fn next_flit(&self) -> Option<(u64, bool, u8)> {
@@ -203,7 +142,7 @@ impl Socket {
         let flit = match packet_cur_lock.pop_front() {
             Some(x) => x,
             None => {
-                match self.packets_in.pop() {
+                match self.receive() {
                     Some(x) => {
                         *packet_cur_lock = chunkize_packet(&x[..]);
                         let remainder = x.len() % 8;
@@ -258,7 +197,7 @@ impl Socket {
 
         if last {
             let p: Vec<u8> = take(lock.as_mut());
-            self.packets_out.push(p);
+            self.send(p);
         }
     }
 }